@@ -0,0 +1,521 @@
+use std::io::Cursor;
+
+#[cfg(feature = "gif")]
+use image::codecs::gif::GifDecoder;
+use image::{
+    AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat, ImageReader,
+    codecs::{png::PngDecoder, webp::WebPDecoder},
+    metadata::Orientation,
+};
+use wasm_bindgen::JsValue;
+
+use crate::{
+    image_color::{convert_to_srgb, srgb_icc_profile},
+    image_export::{encode_webp, near_lossless, png_encoder, png_error},
+    image_limits::ProcessingLimits,
+    image_options::{ImageExportFormat, ImageExportOptions},
+};
+
+/// WebP VP8X flags.
+const WEBP_FLAG_ICC: u8 = 0x20;
+const WEBP_FLAG_ALPHA: u8 = 0x10;
+const WEBP_FLAG_XMP: u8 = 0x04;
+const WEBP_FLAG_ANIMATION: u8 = 0x02;
+/// ANMF flag to replace the canvas instead of alpha blending onto it.
+const WEBP_FRAME_NO_BLEND: u8 = 0x02;
+
+/// A single frame of an animation, with its delay in milliseconds.
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    pub delay_ms: u32,
+}
+
+/// The decoded frames of an animation.
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// The EXIF orientation, which applies to every frame.
+    pub orientation: Orientation,
+}
+
+/// Decode all frames of an animated GIF, PNG or WebP.
+/// Returns `None` if the image is not animated.
+pub fn decode_animation(
+    image_data: &[u8],
+    limits: &ProcessingLimits,
+) -> Result<Option<Animation>, JsValue> {
+    let format = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| JsValue::from_str(&format!("Failed to read image: {e}")))?
+        .format();
+
+    let icc_profile;
+    let orientation;
+    let frames = match format {
+        #[cfg(feature = "gif")]
+        Some(ImageFormat::Gif) => {
+            let mut decoder = GifDecoder::new(Cursor::new(image_data)).map_err(decode_error)?;
+            limits.apply(&mut decoder)?;
+            icc_profile = decoder.icc_profile().map_err(decode_error)?;
+            orientation = decoder.orientation().map_err(decode_error)?;
+            decoder.into_frames()
+        }
+        Some(ImageFormat::Png) => {
+            let mut decoder = PngDecoder::new(Cursor::new(image_data)).map_err(decode_error)?;
+            if !decoder.is_apng().map_err(decode_error)? {
+                return Ok(None);
+            }
+            limits.apply(&mut decoder)?;
+            icc_profile = decoder.icc_profile().map_err(decode_error)?;
+            orientation = decoder.orientation().map_err(decode_error)?;
+            decoder.apng().map_err(decode_error)?.into_frames()
+        }
+        Some(ImageFormat::WebP) => {
+            let mut decoder = WebPDecoder::new(Cursor::new(image_data)).map_err(decode_error)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            limits.apply(&mut decoder)?;
+            icc_profile = decoder.icc_profile().map_err(decode_error)?;
+            orientation = decoder.orientation().map_err(decode_error)?;
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    // the limits only cover a single frame, so keep track of all of them
+    let mut total_bytes = 0u64;
+    let mut decoded = Vec::new();
+    for frame in frames {
+        let frame = frame.map_err(|e| limits.decode_error(e))?;
+        total_bytes += frame.buffer().len() as u64;
+        limits.check_alloc(total_bytes)?;
+
+        let frame = into_animation_frame(frame);
+        decoded.push(AnimationFrame {
+            image: convert_to_srgb(frame.image, icc_profile.as_deref()),
+            ..frame
+        });
+    }
+
+    // a GIF with a single frame is not really animated
+    if decoded.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(Animation {
+        frames: decoded,
+        orientation,
+    }))
+}
+
+/// Encode the frames as an animated PNG or WebP, optionally embedding an XMP
+/// packet.
+pub fn animation_to_bytes(
+    frames: &[AnimationFrame],
+    options: &ImageExportOptions,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    match &options.format {
+        ImageExportFormat::Png => encode_apng(frames, options, xmp),
+        ImageExportFormat::WebpLossless => encode_animated_webp(frames, options, None, xmp),
+        ImageExportFormat::WebpNearLossless { quality } => {
+            encode_animated_webp(frames, options, Some(*quality), xmp)
+        }
+        ImageExportFormat::Avif { .. } => Err(JsValue::from_str(
+            "Animated export is only supported for PNG and WebP",
+        )),
+    }
+}
+
+fn into_animation_frame(frame: Frame) -> AnimationFrame {
+    let (numerator, denominator) = frame.delay().numer_denom_ms();
+
+    AnimationFrame {
+        delay_ms: numerator / denominator.max(1),
+        image: DynamicImage::ImageRgba8(frame.into_buffer()),
+    }
+}
+
+fn decode_error(e: image::ImageError) -> JsValue {
+    JsValue::from_str(&format!("Failed to decode animation: {e}"))
+}
+
+fn encode_apng(
+    frames: &[AnimationFrame],
+    options: &ImageExportOptions,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    let Some(first) = frames.first() else {
+        return Err(JsValue::from_str("Animation has no frames"));
+    };
+
+    let mut bytes = Vec::new();
+    let mut encoder = png_encoder(
+        &mut bytes,
+        first.image.width(),
+        first.image.height(),
+        options,
+        xmp,
+    )?;
+    // loop forever
+    encoder
+        .set_animated(frames.len() as u32, 0)
+        .map_err(png_error)?;
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    for frame in frames {
+        let delay = frame.delay_ms.min(u16::MAX as u32) as u16;
+        writer.set_frame_delay(delay, 1000).map_err(png_error)?;
+        writer
+            .write_image_data(frame.image.to_rgba8().as_raw())
+            .map_err(png_error)?;
+    }
+    writer.finish().map_err(png_error)?;
+
+    Ok(bytes)
+}
+
+/// The WebP encoder can only write single images, so every frame is encoded
+/// on its own and the resulting bitstreams are wrapped into an animated WebP
+/// container.
+fn encode_animated_webp(
+    frames: &[AnimationFrame],
+    options: &ImageExportOptions,
+    lossy_quality: Option<u8>,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    let Some(first) = frames.first() else {
+        return Err(JsValue::from_str("Animation has no frames"));
+    };
+    let (width, height) = (first.image.width(), first.image.height());
+
+    let icc_profile = options.srgb_profile.then(srgb_icc_profile);
+
+    let mut flags = WEBP_FLAG_ALPHA | WEBP_FLAG_ANIMATION;
+    if icc_profile.is_some() {
+        flags |= WEBP_FLAG_ICC;
+    }
+    if xmp.is_some() {
+        flags |= WEBP_FLAG_XMP;
+    }
+
+    let mut vp8x = vec![flags, 0, 0, 0];
+    vp8x.extend_from_slice(&u24(width - 1));
+    vp8x.extend_from_slice(&u24(height - 1));
+
+    // transparent background, loop forever
+    let anim = [0, 0, 0, 0, 0, 0];
+
+    let mut body = Vec::new();
+    body.extend_from_slice(b"WEBP");
+    write_chunk(&mut body, b"VP8X", &vp8x);
+    if let Some(icc_profile) = &icc_profile {
+        write_chunk(&mut body, b"ICCP", icc_profile);
+    }
+    write_chunk(&mut body, b"ANIM", &anim);
+
+    for frame in frames {
+        let image = match lossy_quality {
+            Some(quality) => near_lossless(&frame.image, quality),
+            None => frame.image.clone(),
+        };
+        let encoded = encode_webp(&image.to_rgba8(), options.compression, None, None)?;
+        let bitstream = find_chunk(&encoded, b"VP8L")
+            .ok_or_else(|| JsValue::from_str("Failed to encode animation frame"))?;
+
+        let mut anmf = Vec::with_capacity(16 + 8 + bitstream.len());
+        // frame position, the frames always cover the whole canvas
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(0));
+        anmf.extend_from_slice(&u24(image.width() - 1));
+        anmf.extend_from_slice(&u24(image.height() - 1));
+        anmf.extend_from_slice(&u24(frame.delay_ms.min(0xFF_FFFF)));
+        anmf.push(WEBP_FRAME_NO_BLEND);
+        write_chunk(&mut anmf, b"VP8L", bitstream);
+
+        write_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    if let Some(xmp) = xmp {
+        write_chunk(&mut body, b"XMP ", xmp.as_bytes());
+    }
+
+    let mut bytes = Vec::with_capacity(8 + body.len());
+    bytes.extend_from_slice(b"RIFF");
+    bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&body);
+
+    Ok(bytes)
+}
+
+fn u24(value: u32) -> [u8; 3] {
+    let bytes = value.to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+}
+
+/// Write a RIFF chunk, padding it to an even size.
+fn write_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Find the payload of the first chunk with the given FourCC in a WebP file.
+fn find_chunk<'a>(webp: &'a [u8], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    webp_chunks(webp)
+        .find(|(chunk, _)| chunk == fourcc)
+        .map(|(_, data)| data)
+}
+
+/// Iterate over the FourCC and payload of the chunks in a WebP file, up to
+/// the first chunk that is cut off.
+fn webp_chunks(webp: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    // skip the RIFF header
+    let mut offset: usize = 12;
+
+    std::iter::from_fn(move || {
+        let header = webp.get(offset..offset.checked_add(8)?)?;
+        let size = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let start = offset + 8;
+        let data = webp.get(start..start.checked_add(size)?)?;
+
+        offset = start + size + size % 2;
+        Some((&header[..4], data))
+    })
+}
+
+/// Whether the image is an animated GIF, PNG or WebP with at least two
+/// frames, which is when [`decode_animation`] returns the frames.
+/// Only the headers are read, nothing is decoded.
+pub fn is_animated(image_data: &[u8]) -> bool {
+    if image_data.starts_with(b"GIF87a") || image_data.starts_with(b"GIF89a") {
+        cfg!(feature = "gif") && gif_frame_count(image_data, 2) >= 2
+    } else if image_data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        apng_frame_count(image_data) >= 2
+    } else if image_data.starts_with(b"RIFF") && image_data.get(8..12) == Some(b"WEBP") {
+        let animated = find_chunk(image_data, b"VP8X")
+            .and_then(|vp8x| vp8x.first())
+            .is_some_and(|flags| flags & WEBP_FLAG_ANIMATION != 0);
+
+        animated
+            && webp_chunks(image_data)
+                .filter(|(chunk, _)| *chunk == b"ANMF")
+                .nth(1)
+                .is_some()
+    } else {
+        false
+    }
+}
+
+/// Count the image descriptors of a GIF by walking its blocks, stopping
+/// early once `max` frames are found.
+fn gif_frame_count(gif: &[u8], max: usize) -> usize {
+    /// The size of a color table from the packed fields of a descriptor.
+    fn color_table_size(packed: u8) -> usize {
+        if packed & 0x80 != 0 {
+            3 << ((packed & 0x07) + 1)
+        } else {
+            0
+        }
+    }
+
+    /// Skip a sequence of data sub-blocks, returning the offset after it.
+    fn skip_sub_blocks(gif: &[u8], mut offset: usize) -> Option<usize> {
+        loop {
+            let size = *gif.get(offset)? as usize;
+            offset += 1 + size;
+            if size == 0 {
+                return Some(offset);
+            }
+        }
+    }
+
+    // header and logical screen descriptor
+    let Some(&packed) = gif.get(10) else {
+        return 0;
+    };
+    let mut offset = 13 + color_table_size(packed);
+    let mut frames = 0;
+
+    while frames < max {
+        let next = match gif.get(offset) {
+            // image descriptor, followed by the LZW code size and the data
+            Some(0x2C) => gif.get(offset + 9).and_then(|&packed| {
+                frames += 1;
+                skip_sub_blocks(gif, offset + 10 + color_table_size(packed) + 1)
+            }),
+            // extension, with a label and data
+            Some(0x21) => skip_sub_blocks(gif, offset + 2),
+            // trailer or garbage
+            _ => None,
+        };
+        match next {
+            Some(next) => offset = next,
+            None => break,
+        }
+    }
+
+    frames
+}
+
+/// The number of frames of an APNG from its `acTL` chunk, which has to come
+/// before the image data. Plain PNGs have no frames.
+fn apng_frame_count(png: &[u8]) -> u32 {
+    // skip the signature
+    let mut offset = 8;
+
+    while let Some(header) = png.get(offset..offset + 8) {
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let data = offset + 8;
+        match &header[4..] {
+            b"acTL" => {
+                return png
+                    .get(data..data + 4)
+                    .map_or(0, |frames| u32::from_be_bytes(frames.try_into().unwrap()));
+            }
+            b"IDAT" => return 0,
+            _ => {}
+        }
+        // chunk data and CRC
+        offset = data.saturating_add(size).saturating_add(4);
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn frames() -> Vec<AnimationFrame> {
+        [
+            Rgba([255, 0, 0, 255]),
+            Rgba([0, 255, 0, 128]),
+            Rgba([0, 0, 255, 0]),
+        ]
+        .into_iter()
+        .enumerate()
+        .map(|(i, color)| AnimationFrame {
+            image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(3, 2, color)),
+            delay_ms: 40 * (i as u32 + 1),
+        })
+        .collect()
+    }
+
+    fn options(format: ImageExportFormat) -> ImageExportOptions {
+        ImageExportOptions {
+            format,
+            ..ImageExportOptions::default()
+        }
+    }
+
+    fn assert_round_trip(bytes: &[u8]) {
+        let decoded = decode_animation(bytes, &ProcessingLimits::default())
+            .unwrap()
+            .unwrap()
+            .frames;
+
+        assert_eq!(decoded.len(), 3);
+        for (decoded, original) in decoded.iter().zip(frames()) {
+            assert_eq!(decoded.delay_ms, original.delay_ms);
+            // fully transparent pixels may lose their colour
+            let (decoded, original) = (decoded.image.to_rgba8(), original.image.to_rgba8());
+            assert!(
+                decoded
+                    .pixels()
+                    .zip(original.pixels())
+                    .all(|(a, b)| { a == b || (a[3] == 0 && b[3] == 0) })
+            );
+        }
+    }
+
+    fn encode_png(image: &RgbaImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn apng_round_trips() {
+        let bytes = animation_to_bytes(&frames(), &options(ImageExportFormat::Png), None).unwrap();
+
+        assert!(is_animated(&bytes));
+        assert_round_trip(&bytes);
+    }
+
+    #[test]
+    fn animated_webp_round_trips() {
+        let options = ImageExportOptions {
+            srgb_profile: true,
+            ..options(ImageExportFormat::WebpLossless)
+        };
+        let bytes = animation_to_bytes(&frames(), &options, Some("<x:xmpmeta/>")).unwrap();
+
+        assert!(is_animated(&bytes));
+        let vp8x = find_chunk(&bytes, b"VP8X").unwrap();
+        assert_eq!(
+            vp8x[0],
+            WEBP_FLAG_ICC | WEBP_FLAG_ALPHA | WEBP_FLAG_XMP | WEBP_FLAG_ANIMATION
+        );
+        assert_eq!(find_chunk(&bytes, b"XMP "), Some(&b"<x:xmpmeta/>"[..]));
+        assert_round_trip(&bytes);
+    }
+
+    #[test]
+    fn still_images_are_not_animated() {
+        let image = RgbaImage::from_pixel(3, 2, Rgba([1, 2, 3, 4]));
+        let png = encode_png(&image);
+        assert!(!is_animated(&png));
+
+        let webp = encode_webp(&image, 6, None, None).unwrap();
+        assert!(!is_animated(&webp));
+
+        // an APNG with a single frame
+        let single = animation_to_bytes(&frames()[..1], &options(ImageExportFormat::Png), None);
+        assert!(!is_animated(&single.unwrap()));
+
+        assert!(!is_animated(b"not an image"));
+        assert!(!is_animated(&png[..png.len() / 2]));
+    }
+
+    #[test]
+    fn apng_frames_are_only_counted_before_the_image_data() {
+        let apng = animation_to_bytes(&frames(), &options(ImageExportFormat::Png), None).unwrap();
+        assert_eq!(apng_frame_count(&apng), 3);
+
+        let png = encode_png(&RgbaImage::new(1, 1));
+        assert_eq!(apng_frame_count(&png), 0);
+    }
+
+    #[cfg(feature = "gif")]
+    #[test]
+    fn gif_frames_are_counted() {
+        use image::codecs::gif::GifEncoder;
+
+        let encode = |frames: Vec<AnimationFrame>| {
+            let mut bytes = Vec::new();
+            let mut encoder = GifEncoder::new(&mut bytes);
+            for frame in frames {
+                encoder
+                    .encode_frame(Frame::new(frame.image.to_rgba8()))
+                    .unwrap();
+            }
+            drop(encoder);
+            bytes
+        };
+
+        let animated = encode(frames());
+        assert_eq!(gif_frame_count(&animated, usize::MAX), 3);
+        assert!(is_animated(&animated));
+
+        let still = encode(frames().into_iter().take(1).collect());
+        assert!(!is_animated(&still));
+    }
+}
@@ -2,13 +2,16 @@ use std::{collections::HashMap, io::Cursor};
 
 use hex_color::HexColor;
 use image::{
-    DynamicImage, GenericImageView, ImageBuffer, ImageReader, Rgba,
+    DynamicImage, GenericImageView, ImageBuffer, ImageFormat, Rgba,
     imageops::{self, FilterType},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::JsValue;
 
-use crate::{create_blank_image, image_options::ImageDimensions};
+use crate::{
+    create_blank_image, decode_limited, image_limits::ProcessingLimits,
+    image_options::ImageDimensions,
+};
 
 pub struct ImageBorder {
     sprite_sheet: DynamicImage,
@@ -18,34 +21,36 @@ pub struct ImageBorder {
 }
 
 impl ImageBorder {
-    pub fn from_js(img: &[u8], config: String) -> Result<Self, JsValue> {
-        let image: DynamicImage = ImageReader::new(Cursor::new(img))
-            .with_guessed_format()
-            .map_err(|e| JsValue::from_str(&format!("Failed to read image: {e}")))?
-            .decode()
-            .map_err(|e| JsValue::from_str(&format!("Failed to decode image: {e}")))?;
-
-        let config: SpriteSheetConfig = serde_json::from_str(&config)
+    pub fn from_js(img: &[u8], config: String, limits: &ProcessingLimits) -> Result<Self, JsValue> {
+        let image = decode_limited(img, limits)?;
+
+        let sprite_sheet_config: SpriteSheetConfig = serde_json::from_str(&config)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse config: {e}")))?;
 
-        let mut ring_frames: Vec<RingFrame> = config
+        let mut ring_frames: Vec<RingFrame> = sprite_sheet_config
             .frames
-            .values()
-            .filter_map(|frame| {
+            .iter()
+            .filter_map(|(name, frame)| {
                 if let Frame::Ring(ring_frame) = frame {
-                    Some(ring_frame.clone())
+                    Some(RingFrame {
+                        name: name.clone(),
+                        ..ring_frame.clone()
+                    })
                 } else {
                     None
                 }
             })
             .collect();
 
-        let mut bkg_frames: Vec<BkgFrame> = config
+        let mut bkg_frames: Vec<BkgFrame> = sprite_sheet_config
             .frames
-            .values()
-            .filter_map(|frame| {
+            .iter()
+            .filter_map(|(name, frame)| {
                 if let Frame::Bkg(bkg_frame) = frame {
-                    Some(bkg_frame.clone())
+                    Some(BkgFrame {
+                        name: name.clone(),
+                        ..bkg_frame.clone()
+                    })
                 } else {
                     None
                 }
@@ -57,27 +62,125 @@ impl ImageBorder {
 
         Ok(ImageBorder {
             sprite_sheet: image,
-            config: config.config,
+            config: sprite_sheet_config.config,
             ring_frames,
             bkg_frames,
         })
     }
 
+    /// Encode the sprite sheet as PNG and serialize the config again, so that
+    /// the border can be stored, e.g. in project files.
+    pub fn get_source(&self) -> Result<(Vec<u8>, String), JsValue> {
+        let mut image = Vec::new();
+        self.sprite_sheet
+            .write_to(&mut Cursor::new(&mut image), ImageFormat::Png)
+            .map_err(|e| JsValue::from_str(&format!("Failed to write ring image: {e}")))?;
+
+        let frames = self
+            .ring_frames
+            .iter()
+            .map(|frame| (frame.name.clone(), Frame::Ring(frame.clone())))
+            .chain(
+                self.bkg_frames
+                    .iter()
+                    .map(|frame| (frame.name.clone(), Frame::Bkg(frame.clone()))),
+            )
+            .collect();
+        let config = serde_json::to_string(&SpriteSheetConfig {
+            config: self.config.clone(),
+            frames,
+        })
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize ring config: {e}")))?;
+
+        Ok((image, config))
+    }
+
     pub fn get_ring(
         &self,
         dimensions: &ImageDimensions,
+        grid_target: Option<u32>,
+        filter: FilterType,
     ) -> Result<(DynamicImage, DynamicImage), JsValue> {
-        let (bkg_frame, ring_frame) = self.get_frame_for_dimensions(dimensions)?;
+        let (bkg_frame, ring_frame) = self.get_frame_for_dimensions(dimensions, grid_target)?;
 
-        let bkg_image = self.cut_and_scale_bkg(bkg_frame, dimensions);
-        let ring_image = self.cut_and_scale_ring(ring_frame, dimensions)?;
+        let bkg_image = self.cut_and_scale_bkg(bkg_frame, dimensions, filter);
+        let ring_image = self.cut_and_scale_ring(ring_frame, dimensions, filter)?;
 
         Ok((bkg_image, ring_image))
     }
 
+    /// The grid targets of all ring frames, sorted, each with the width of
+    /// the smallest frame that is used for it.
+    pub fn get_grid_targets(&self) -> Vec<(u32, u32)> {
+        let mut grid_targets: Vec<(u32, u32)> = self
+            .ring_frames
+            .iter()
+            .map(|frame| (frame.grid_target, frame.frame.width))
+            .collect();
+        // the sort is stable, so the smallest frame of each target stays first
+        grid_targets.sort_by_key(|(grid_target, _)| *grid_target);
+        grid_targets.dedup_by_key(|(grid_target, _)| *grid_target);
+
+        grid_targets
+    }
+
+    /// Describe the ring that is used for the given dimensions.
+    pub fn get_ring_info(
+        &self,
+        dimensions: &ImageDimensions,
+        grid_target: Option<u32>,
+    ) -> Result<RingInfo, JsValue> {
+        let (_, ring_frame) = self.get_frame_for_dimensions(dimensions, grid_target)?;
+
+        Ok(RingInfo {
+            frame: ring_frame.name.clone(),
+            grid_target: ring_frame.grid_target,
+            color: self.config.default_ring_color.clone(),
+        })
+    }
+
+    /// The radius of the inside of the ring for the given dimensions, relative
+    /// to half the token size.
+    /// The outside of the ring is where its trimmed sprite ends, the ring
+    /// thickness is measured inwards from there. Frames without a thickness
+    /// fall back to the start of the color band.
+    pub fn get_inner_radius(
+        &self,
+        dimensions: &ImageDimensions,
+        grid_target: Option<u32>,
+    ) -> Result<f32, JsValue> {
+        let (_, ring_frame) = self.get_frame_for_dimensions(dimensions, grid_target)?;
+
+        let Some(ring_thickness) = ring_frame
+            .ring_thickness
+            .or(self.config.default_ring_thickness)
+        else {
+            return Ok(self.get_color_band(ring_frame).start_radius);
+        };
+
+        let source_size = ring_frame.source_size.x.max(ring_frame.source_size.y);
+        if source_size <= 0 {
+            return Err(JsValue::from_str(&format!(
+                "Ring frame {} has no source size",
+                ring_frame.name
+            )));
+        }
+        let ring_size = ring_frame
+            .sprite_source_size
+            .width
+            .max(ring_frame.sprite_source_size.height);
+        let outer_radius = ring_size as f32 / source_size as f32;
+
+        Ok((outer_radius - ring_thickness).max(0.0))
+    }
+
+    /// The smallest background and ring frames that are at least as large as
+    /// the token. With a grid target, the smallest ring frame for it is used
+    /// instead, whatever the size of the token.
     fn get_frame_for_dimensions(
         &self,
         dimensions: &ImageDimensions,
+        grid_target: Option<u32>,
     ) -> Result<(&BkgFrame, &RingFrame), JsValue> {
         let token_size = dimensions.token_size();
 
@@ -85,10 +188,20 @@ impl ImageBorder {
             .bkg_frames
             .iter()
             .find(|frame| frame.frame.width >= token_size);
-        let best_ring_frame = self
-            .ring_frames
-            .iter()
-            .find(|frame| frame.frame.width >= token_size);
+        let best_ring_frame = match grid_target {
+            Some(grid_target) => Some(
+                self.ring_frames
+                    .iter()
+                    .find(|frame| frame.grid_target == grid_target)
+                    .ok_or_else(|| {
+                        JsValue::from_str(&format!("No ring frame for grid target {grid_target}"))
+                    })?,
+            ),
+            None => self
+                .ring_frames
+                .iter()
+                .find(|frame| frame.frame.width >= token_size),
+        };
 
         match (best_bkg_frame, best_ring_frame) {
             (Some(bkg_frame), Some(ring_frame)) => Ok((bkg_frame, ring_frame)),
@@ -101,7 +214,12 @@ impl ImageBorder {
         }
     }
 
-    fn cut_and_scale_bkg(&self, frame: &BkgFrame, dimensions: &ImageDimensions) -> DynamicImage {
+    fn cut_and_scale_bkg(
+        &self,
+        frame: &BkgFrame,
+        dimensions: &ImageDimensions,
+        filter: FilterType,
+    ) -> DynamicImage {
         let cut_image = self.sprite_sheet.crop_imm(
             frame.frame.x as u32,
             frame.frame.y as u32,
@@ -109,13 +227,14 @@ impl ImageBorder {
             frame.frame.height,
         );
 
-        self.scale_img(cut_image, dimensions)
+        self.scale_img(cut_image, dimensions, filter)
     }
 
     fn cut_and_scale_ring(
         &self,
         frame: &RingFrame,
         dimensions: &ImageDimensions,
+        filter: FilterType,
     ) -> Result<DynamicImage, JsValue> {
         let cut_image = self.sprite_sheet.crop_imm(
             frame.frame.x as u32,
@@ -148,16 +267,21 @@ impl ImageBorder {
             cut_image
         };
 
-        Ok(self.scale_img(cut_image, dimensions))
+        Ok(self.scale_img(cut_image, dimensions, filter))
     }
 
-    fn scale_img(&self, image: DynamicImage, dimensions: &ImageDimensions) -> DynamicImage {
+    fn scale_img(
+        &self,
+        image: DynamicImage,
+        dimensions: &ImageDimensions,
+        filter: FilterType,
+    ) -> DynamicImage {
         let token_size = dimensions.token_size();
 
         let scaled_img = if image.width() == token_size && image.height() == token_size {
             image
         } else {
-            image.resize(token_size, token_size, FilterType::CatmullRom)
+            image.resize(token_size, token_size, filter)
         };
 
         if dimensions.oversized {
@@ -210,7 +334,7 @@ impl ImageBorder {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Point {
     #[serde(alias = "w")]
@@ -219,7 +343,7 @@ pub struct Point {
     pub y: i32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct FloatPoint {
     #[serde(alias = "w")]
@@ -228,7 +352,7 @@ pub struct FloatPoint {
     pub y: f32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Box {
     pub x: i32,
@@ -239,7 +363,7 @@ pub struct Box {
     pub height: u32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct ColorBand {
     #[serde(rename = "startRadius")]
@@ -248,9 +372,12 @@ pub struct ColorBand {
     pub end_radius: f32,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct BkgFrame {
+    /// The key of the frame in the sprite sheet.
+    #[serde(skip)]
+    pub name: String,
     pub frame: Box,
     pub rotated: bool,
     pub trimmed: bool,
@@ -261,9 +388,12 @@ pub struct BkgFrame {
     pub anchor: FloatPoint,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct RingFrame {
+    /// The key of the frame in the sprite sheet.
+    #[serde(skip)]
+    pub name: String,
     pub frame: Box,
     pub rotated: bool,
     pub trimmed: bool,
@@ -276,11 +406,12 @@ pub struct RingFrame {
     pub grid_target: u32,
     #[serde(rename = "colorBand")]
     pub color_band: Option<ColorBand>,
+    /// The thickness of the ring, relative to half the frame size.
     #[serde(rename = "ringThickness")]
     pub ring_thickness: Option<f32>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 #[allow(unused)]
 pub enum Frame {
@@ -288,18 +419,129 @@ pub enum Frame {
     Bkg(BkgFrame),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// Which ring was used for a render.
+#[derive(Debug, Clone, Serialize)]
+pub struct RingInfo {
+    pub frame: String,
+    pub grid_target: u32,
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct RingConfig {
     #[serde(rename = "defaultColorBand")]
     default_color_band: ColorBand,
     #[serde(rename = "defaultRingColor")]
     default_ring_color: Option<String>, // Hex color code
+    /// The thickness of rings whose frame has none, relative to half the
+    /// frame size.
+    #[serde(rename = "defaultRingThickness")]
+    default_ring_thickness: Option<f32>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct SpriteSheetConfig {
     pub config: RingConfig,
     pub frames: HashMap<String, Frame>,
 }
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use super::*;
+
+    fn frame(x: u32, size: u32, grid_target: Option<u32>) -> String {
+        let grid_target = grid_target
+            .map(|grid_target| format!(r#", "gridTarget": {grid_target}"#))
+            .unwrap_or_default();
+
+        format!(
+            r#"{{
+                "frame": {{ "x": {x}, "y": 0, "w": {size}, "h": {size} }},
+                "rotated": false,
+                "trimmed": false,
+                "spriteSourceSize": {{ "x": 0, "y": 0, "w": {size}, "h": {size} }},
+                "sourceSize": {{ "w": {size}, "h": {size} }},
+                "anchor": {{ "x": 0.5, "y": 0.5 }}{grid_target}
+            }}"#
+        )
+    }
+
+    /// Ring frames whose grid targets are not in the order of their sizes.
+    fn border() -> ImageBorder {
+        let config = format!(
+            r#"{{
+                "config": {{ "defaultColorBand": {{ "startRadius": 0.8, "endRadius": 0.9 }} }},
+                "frames": {{
+                    "ring-small": {},
+                    "ring-medium": {},
+                    "ring-large": {},
+                    "bkg-small": {},
+                    "bkg-large": {}
+                }}
+            }}"#,
+            frame(0, 4, Some(1)),
+            frame(4, 8, Some(2)),
+            frame(12, 16, Some(1)),
+            frame(28, 8, None),
+            frame(36, 16, None),
+        );
+        let mut sheet = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(52, 16))
+            .write_to(&mut Cursor::new(&mut sheet), ImageFormat::Png)
+            .unwrap();
+
+        ImageBorder::from_js(&sheet, config, &ProcessingLimits::default()).unwrap()
+    }
+
+    fn dimensions(size: u32) -> ImageDimensions {
+        ImageDimensions {
+            size,
+            oversized: false,
+            stencil_radius: size / 2,
+        }
+    }
+
+    #[test]
+    fn grid_targets_are_unique_and_sorted() {
+        assert_eq!(border().get_grid_targets(), vec![(1, 4), (2, 8)]);
+    }
+
+    #[test]
+    fn frames_are_selected_by_size_without_a_grid_target() {
+        let border = border();
+
+        for (size, frame) in [(4, "ring-small"), (6, "ring-medium"), (12, "ring-large")] {
+            let info = border.get_ring_info(&dimensions(size), None).unwrap();
+            assert_eq!(info.frame, frame);
+        }
+    }
+
+    #[test]
+    fn frames_are_selected_by_grid_target() {
+        let border = border();
+
+        for (grid_target, frame) in [(1, "ring-small"), (2, "ring-medium")] {
+            let info = border
+                .get_ring_info(&dimensions(16), Some(grid_target))
+                .unwrap();
+            assert_eq!(info.frame, frame);
+            assert_eq!(info.grid_target, grid_target);
+        }
+    }
+
+    #[test]
+    fn inner_radius_is_measured_inwards_from_the_outside_of_the_ring() {
+        let mut border = border();
+        assert_eq!(border.get_inner_radius(&dimensions(16), None).unwrap(), 0.8);
+
+        border.config.default_ring_thickness = Some(0.25);
+        assert_eq!(
+            border.get_inner_radius(&dimensions(16), None).unwrap(),
+            0.75
+        );
+    }
+}
@@ -0,0 +1,49 @@
+use image::{DynamicImage, ImageDecoder, RgbaImage};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+/// Read the embedded ICC profile from the decoder and decode the image,
+/// converting it to sRGB.
+pub fn decode_to_srgb(mut decoder: impl ImageDecoder) -> image::ImageResult<DynamicImage> {
+    let icc_profile = decoder.icc_profile()?;
+    let image = DynamicImage::from_decoder(decoder)?;
+
+    Ok(convert_to_srgb(image, icc_profile.as_deref()))
+}
+
+/// Convert an image with the given ICC profile to sRGB.
+/// Images without a profile are assumed to already be sRGB. Profiles that
+/// are invalid or not RGB are ignored, since there is nothing sensible we
+/// could convert them to.
+pub fn convert_to_srgb(image: DynamicImage, icc_profile: Option<&[u8]>) -> DynamicImage {
+    let Some(icc_profile) = icc_profile else {
+        return image;
+    };
+    let Ok(profile) = ColorProfile::new_from_slice(icc_profile) else {
+        return image;
+    };
+    if profile.color_space != DataColorSpace::Rgb {
+        return image;
+    }
+
+    let Ok(transform) = profile.create_transform_8bit(
+        Layout::Rgba,
+        &ColorProfile::new_srgb(),
+        Layout::Rgba,
+        TransformOptions::default(),
+    ) else {
+        return image;
+    };
+
+    let source = image.into_rgba8();
+    let mut converted = RgbaImage::new(source.width(), source.height());
+    match transform.transform(source.as_raw(), &mut converted) {
+        Ok(()) => converted.into(),
+        Err(_) => source.into(),
+    }
+}
+
+/// An ICC profile for sRGB, to tag exported images with.
+pub fn srgb_icc_profile() -> Vec<u8> {
+    // encoding our own built-in profile can not fail
+    ColorProfile::new_srgb().encode().unwrap_or_default()
+}
@@ -0,0 +1,315 @@
+use std::{
+    collections::HashSet,
+    io::{Cursor, Write},
+};
+
+#[cfg(feature = "avif")]
+use image::codecs::avif::AvifEncoder;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgba, RgbaImage};
+use image_webp::{ColorType, EncoderParams, WebPEncoder};
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::{
+    image_border::RingInfo,
+    image_color::srgb_icc_profile,
+    image_options::{ImageExportFormat, ImageExportOptions, ImageRenderOptions},
+};
+
+/// The keyword of the PNG text chunk that holds XMP metadata.
+const PNG_XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+/// The name of the manifest file in ZIP exports.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ZipManifest {
+    pub files: Vec<ZipManifestEntry>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ZipManifestEntry {
+    pub file: String,
+    pub options: ImageRenderOptions,
+    pub ring: Option<RingInfo>,
+}
+
+/// Encode the image into the format described by the export options.
+/// Optionally an XMP packet is embedded into the image, which is only
+/// supported for PNG and WebP.
+pub fn image_to_bytes(
+    image: &DynamicImage,
+    options: &ImageExportOptions,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    let icc_profile = options.srgb_profile.then(srgb_icc_profile);
+
+    match &options.format {
+        ImageExportFormat::Png => encode_png(&image.to_rgba8(), options, xmp),
+        ImageExportFormat::WebpLossless => encode_webp(
+            &image.to_rgba8(),
+            options.compression,
+            icc_profile.as_deref(),
+            xmp,
+        ),
+        ImageExportFormat::WebpNearLossless { quality } => encode_webp(
+            &near_lossless(image, *quality).to_rgba8(),
+            options.compression,
+            icc_profile.as_deref(),
+            xmp,
+        ),
+        ImageExportFormat::Avif { .. } if xmp.is_some() => Err(JsValue::from_str(
+            "Embedding settings is not supported for AVIF export",
+        )),
+        #[cfg(feature = "avif")]
+        ImageExportFormat::Avif { quality } => {
+            let mut bytes = Vec::new();
+            // AVIF speed goes from 1 (slowest) to 10 (fastest)
+            let speed = 10 - options.compression.min(9);
+            let encoder = AvifEncoder::new_with_speed_quality(
+                Cursor::new(&mut bytes),
+                speed,
+                (*quality).min(100),
+            );
+            image
+                .write_with_encoder(encoder)
+                .map_err(|e| JsValue::from_str(&format!("Failed to write image: {e}")))?;
+            Ok(bytes)
+        }
+        #[cfg(not(feature = "avif"))]
+        ImageExportFormat::Avif { .. } => Err(JsValue::from_str(
+            "AVIF export is not supported by this build",
+        )),
+    }
+}
+
+/// Create an RGBA PNG encoder with the compression and sRGB tag from the
+/// export options, optionally embedding an XMP packet.
+pub fn png_encoder<W: Write>(
+    w: W,
+    width: u32,
+    height: u32,
+    options: &ImageExportOptions,
+    xmp: Option<&str>,
+) -> Result<png::Encoder<'static, W>, JsValue> {
+    let compression = options.compression.min(9);
+
+    let mut encoder = png::Encoder::new(w, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_filter(png::Filter::Adaptive);
+    encoder.set_deflate_compression(if compression == 0 {
+        png::DeflateCompression::NoCompression
+    } else {
+        png::DeflateCompression::Level(compression)
+    });
+    if options.srgb_profile {
+        encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+    }
+    if let Some(xmp) = xmp {
+        encoder
+            .add_itxt_chunk(PNG_XMP_KEYWORD.to_string(), xmp.to_string())
+            .map_err(png_error)?;
+    }
+
+    Ok(encoder)
+}
+
+pub fn png_error(e: png::EncodingError) -> JsValue {
+    JsValue::from_str(&format!("Failed to write image: {e}"))
+}
+
+fn encode_png(
+    image: &RgbaImage,
+    options: &ImageExportOptions,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    let mut bytes = Vec::new();
+    let encoder = png_encoder(&mut bytes, image.width(), image.height(), options, xmp)?;
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(image.as_raw()).map_err(png_error)?;
+    writer.finish().map_err(png_error)?;
+
+    Ok(bytes)
+}
+
+/// Encode the image as lossless WebP. The encoder only has one tuning knob,
+/// so compression level 0 skips the predictor transform for a faster encode
+/// and every other level uses it.
+pub fn encode_webp(
+    image: &RgbaImage,
+    compression: u8,
+    icc_profile: Option<&[u8]>,
+    xmp: Option<&str>,
+) -> Result<Vec<u8>, JsValue> {
+    let mut bytes = Vec::new();
+    let mut encoder = WebPEncoder::new(&mut bytes);
+    let mut params = EncoderParams::default();
+    params.use_predictor_transform = compression > 0;
+    encoder.set_params(params);
+    if let Some(icc_profile) = icc_profile {
+        encoder.set_icc_profile(icc_profile.to_vec());
+    }
+    if let Some(xmp) = xmp {
+        encoder.set_xmp_metadata(xmp.as_bytes().to_vec());
+    }
+
+    encoder
+        .encode(
+            image.as_raw(),
+            image.width(),
+            image.height(),
+            ColorType::Rgba8,
+        )
+        .map_err(|e| JsValue::from_str(&format!("Failed to write image: {e}")))?;
+
+    Ok(bytes)
+}
+
+/// The image crate can only write lossless WebP, so lossy WebP is done the
+/// way libwebp does near-lossless encoding: the colour channels are quantized
+/// before the lossless encode, which makes the output a lot more
+/// compressible. Fully transparent pixels get their colour dropped entirely.
+pub fn near_lossless(image: &DynamicImage, quality: u8) -> DynamicImage {
+    // quality 100 keeps all bits, quality 0 drops the lowest 5 bits
+    let dropped_bits = (100 - quality.min(100) as u32).div_ceil(20);
+    if dropped_bits == 0 {
+        return image.clone();
+    }
+
+    let step = 1u32 << dropped_bits;
+    let quantize = |value: u8| -> u8 {
+        let rounded = (value as u32 + step / 2) / step * step;
+        rounded.min(255) as u8
+    };
+
+    ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+        let pixel = image.get_pixel(x, y);
+        if pixel[3] == 0 {
+            Rgba([0, 0, 0, 0])
+        } else {
+            Rgba([
+                quantize(pixel[0]),
+                quantize(pixel[1]),
+                quantize(pixel[2]),
+                pixel[3],
+            ])
+        }
+    })
+    .into()
+}
+
+/// Package the encoded images together with a manifest into a ZIP archive.
+pub fn images_to_zip(
+    files: &[(String, Vec<u8>)],
+    manifest: &ZipManifest,
+) -> Result<Vec<u8>, JsValue> {
+    let manifest = serde_json::to_vec_pretty(manifest)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize manifest: {e}")))?;
+
+    let files: Vec<(&str, &[u8])> = files
+        .iter()
+        .map(|(name, data)| (name.as_str(), data.as_slice()))
+        .chain(std::iter::once((MANIFEST_FILE_NAME, manifest.as_slice())))
+        .collect();
+    check_file_names(files.iter().map(|(name, _)| *name))?;
+
+    write_zip(&files)
+}
+
+/// Write the files into a ZIP archive.
+/// The files are stored without compression, since they are mostly images
+/// that are already compressed.
+pub fn write_zip(files: &[(&str, &[u8])]) -> Result<Vec<u8>, JsValue> {
+    let mut bytes = Vec::new();
+    let mut zip = ZipWriter::new(Cursor::new(&mut bytes));
+    let file_options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    for (name, data) in files {
+        zip.start_file(*name, file_options)
+            .map_err(|e| JsValue::from_str(&format!("Failed to add {name} to archive: {e}")))?;
+        zip.write_all(data)
+            .map_err(|e| JsValue::from_str(&format!("Failed to write {name} to archive: {e}")))?;
+    }
+
+    zip.finish()
+        .map_err(|e| JsValue::from_str(&format!("Failed to write archive: {e}")))?;
+    Ok(bytes)
+}
+
+/// Make sure the names can be used as file names in a ZIP archive: every
+/// name has to be unique and may not point outside of the archive's root
+/// directory when it is extracted.
+pub fn check_file_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<(), JsValue> {
+    match file_name_error(names) {
+        Some(error) => Err(JsValue::from_str(&error)),
+        None => Ok(()),
+    }
+}
+
+/// Why the names can't be used as file names in a ZIP archive, if they
+/// can't.
+fn file_name_error<'a>(names: impl IntoIterator<Item = &'a str>) -> Option<String> {
+    let mut seen = HashSet::new();
+
+    for name in names {
+        let invalid = name.is_empty()
+            || name == "."
+            || name == ".."
+            || name
+                .chars()
+                .any(|c| matches!(c, '/' | '\\' | ':') || c.is_control());
+        if invalid {
+            return Some(format!("Invalid file name {name:?} for archive"));
+        }
+        if !seen.insert(name) {
+            return Some(format!("Duplicate file name {name:?} in archive"));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_plain_file_names_are_accepted() {
+        assert_eq!(
+            file_name_error(["token.webp", "token 2.webp", MANIFEST_FILE_NAME]),
+            None
+        );
+        assert_eq!(file_name_error([]), None);
+    }
+
+    #[test]
+    fn file_names_outside_of_the_archive_root_are_rejected() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../token.png",
+            "a/b.png",
+            "a\\b.png",
+            "C:token.png",
+            "a\nb.png",
+        ] {
+            assert_eq!(
+                file_name_error([name]),
+                Some(format!("Invalid file name {name:?} for archive")),
+            );
+        }
+    }
+
+    #[test]
+    fn duplicate_file_names_are_rejected() {
+        assert_eq!(
+            file_name_error(["token.png", "ring.png", "token.png"]),
+            Some("Duplicate file name \"token.png\" in archive".to_string()),
+        );
+        assert!(file_name_error(["token.png", MANIFEST_FILE_NAME, MANIFEST_FILE_NAME]).is_some());
+    }
+}
@@ -0,0 +1,279 @@
+use image::{RgbaImage, imageops::FilterType};
+
+use crate::{
+    image_options::{FramingPreset, ImageDimensions, ImageTransform},
+    image_source::SourceImage,
+};
+
+/// The size of the canvas the subject is analysed on. Framing does not need
+/// more detail than this, and it keeps the analysis fast for large images.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// The part of the alpha mass that is ignored on each side when finding the
+/// extent of the subject, so that stray pixels do not count.
+const OUTLIER_FRACTION: f32 = 0.001;
+
+/// How much of the stencil diameter the framed part of the subject fills
+/// vertically, leaving some room above the head.
+const FILL: f32 = 0.9;
+
+/// Suggest a transform that fits the subject into the stencil circle.
+/// The subject is found from the alpha channel of the image, so for opaque
+/// images the whole image counts as the subject. Only the cropped part of
+/// the image is considered, and the flip of the current transform is kept.
+pub fn suggest_transform(
+    image: &SourceImage,
+    dimensions: &ImageDimensions,
+    preset: FramingPreset,
+    current: &ImageTransform,
+) -> ImageTransform {
+    // only the part of the crop that lies within the image is analysed,
+    // centred on the analysis canvas
+    let (image_width, image_height) = image.oriented_dimensions();
+    let (width, height, offset_x, offset_y) = match &current.crop {
+        Some(crop) => {
+            let clamped = crop.clamped(image_width as f32, image_height as f32);
+            (
+                clamped.width as u32,
+                clamped.height as u32,
+                clamped.x + clamped.width / 2.0 - (crop.x + crop.width / 2.0),
+                clamped.y + clamped.height / 2.0 - (crop.y + crop.height / 2.0),
+            )
+        }
+        None => (image_width, image_height, 0.0, 0.0),
+    };
+    let flipped = current.flipped;
+    let mut transform = ImageTransform {
+        pos_x: 0.0,
+        pos_y: 0.0,
+        scale: 1.0,
+        flipped,
+        rotation: 0.0,
+        crop: current.crop,
+    };
+    if width == 0 || height == 0 || dimensions.stencil_radius == 0 {
+        return transform;
+    }
+
+    // draw the upright image so that it fills the analysis canvas
+    let analysis_scale = ANALYSIS_SIZE as f32 / width.max(height) as f32;
+    let analysis = image.draw(
+        ANALYSIS_SIZE,
+        &ImageTransform {
+            pos_x: -offset_x * analysis_scale,
+            pos_y: -offset_y * analysis_scale,
+            scale: analysis_scale,
+            flipped: false,
+            ..transform.clone()
+        },
+        FilterType::Triangle,
+    );
+
+    let Some((top, bottom)) = extent(&row_mass(&analysis)) else {
+        let scale = dimensions.stencil_radius as f32 * 2.0 / width.max(height) as f32;
+        return ImageTransform {
+            pos_x: if flipped { offset_x } else { -offset_x } * scale,
+            pos_y: -offset_y * scale,
+            scale,
+            ..transform
+        };
+    };
+    let region_bottom = top + (bottom - top) * preset.height_fraction();
+
+    let columns = column_mass(&analysis, top as u32, region_bottom.ceil() as u32);
+    let Some((left, right)) = extent(&columns) else {
+        return transform;
+    };
+    let region_width = (right - left).max(1.0);
+    let region_height = (region_bottom - top).max(1.0);
+
+    let diameter = dimensions.stencil_radius as f32 * 2.0;
+    let (factor, center_x) = match preset {
+        // the whole subject has to fit into the circle, corners included
+        FramingPreset::FullBody => (
+            diameter / region_width.hypot(region_height),
+            (left + right) / 2.0,
+        ),
+        // the upper part fills the circle and the rest is cut off, centred
+        // on where most of the subject is
+        FramingPreset::HeadAndShoulders | FramingPreset::Bust => (
+            (FILL * diameter / region_height).min(diameter / region_width),
+            centroid(&columns).clamp(left, right),
+        ),
+    };
+    let center_y = (top + region_bottom) / 2.0;
+
+    // move the centre of the framed part onto the centre of the stencil,
+    // the analysis canvas was centred on the part of the crop within the
+    // image instead of the crop itself
+    let half = ANALYSIS_SIZE as f32 / 2.0;
+    let pos_x = -(center_x - half + offset_x * analysis_scale) * factor;
+    transform.pos_x = if flipped { -pos_x } else { pos_x };
+    transform.pos_y = -(center_y - half + offset_y * analysis_scale) * factor;
+    transform.scale = analysis_scale * factor;
+
+    transform
+}
+
+/// The alpha mass of each row.
+fn row_mass(image: &RgbaImage) -> Vec<f32> {
+    (0..image.height())
+        .map(|y| {
+            (0..image.width())
+                .map(|x| image.get_pixel(x, y)[3] as f32)
+                .sum()
+        })
+        .collect()
+}
+
+/// The alpha mass of each column, counting only the rows from top to
+/// bottom.
+fn column_mass(image: &RgbaImage, top: u32, bottom: u32) -> Vec<f32> {
+    let bottom = bottom.min(image.height());
+    (0..image.width())
+        .map(|x| (top..bottom).map(|y| image.get_pixel(x, y)[3] as f32).sum())
+        .collect()
+}
+
+/// The start and end of the mass along one axis, without the outliers.
+fn extent(mass: &[f32]) -> Option<(f32, f32)> {
+    let total: f32 = mass.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut cumulative = 0.0;
+    let mut start = None;
+    let mut end = mass.len();
+    for (i, value) in mass.iter().enumerate() {
+        cumulative += value;
+        if start.is_none() && cumulative > total * OUTLIER_FRACTION {
+            start = Some(i);
+        }
+        if cumulative >= total * (1.0 - OUTLIER_FRACTION) {
+            end = i + 1;
+            break;
+        }
+    }
+
+    start.map(|start| (start as f32, end as f32))
+}
+
+/// The centre of mass along one axis.
+fn centroid(mass: &[f32]) -> f32 {
+    let total: f32 = mass.iter().sum();
+    if total <= 0.0 {
+        return mass.len() as f32 / 2.0;
+    }
+
+    mass.iter()
+        .enumerate()
+        .map(|(i, value)| (i as f32 + 0.5) * value)
+        .sum::<f32>()
+        / total
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, Rgba};
+
+    use super::*;
+
+    const SIZE: u32 = 100;
+
+    /// A tall opaque block left of the centre of a transparent image.
+    fn subject() -> SourceImage {
+        let image = RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+            let inside = (20..30).contains(&x) && (10..90).contains(&y);
+            Rgba([255, 255, 255, if inside { 255 } else { 0 }])
+        });
+
+        DynamicImage::ImageRgba8(image).into()
+    }
+
+    /// The bounds of the opaque pixels after drawing with the transform.
+    fn drawn_bounds(image: &SourceImage, transform: &ImageTransform) -> (f32, f32, f32, f32) {
+        let drawn = image.draw(SIZE, transform, FilterType::Triangle);
+        let opaque: Vec<(u32, u32)> = drawn
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[3] > 127)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+
+        (
+            opaque.iter().map(|(x, _)| *x).min().unwrap() as f32,
+            opaque.iter().map(|(_, y)| *y).min().unwrap() as f32,
+            opaque.iter().map(|(x, _)| *x).max().unwrap() as f32 + 1.0,
+            opaque.iter().map(|(_, y)| *y).max().unwrap() as f32 + 1.0,
+        )
+    }
+
+    fn dimensions() -> ImageDimensions {
+        ImageDimensions {
+            size: SIZE,
+            oversized: false,
+            stencil_radius: 40,
+        }
+    }
+
+    fn upright() -> ImageTransform {
+        ImageTransform {
+            pos_x: 0.0,
+            pos_y: 0.0,
+            scale: 1.0,
+            flipped: false,
+            rotation: 0.0,
+            crop: None,
+        }
+    }
+
+    #[test]
+    fn full_body_fits_the_subject_into_the_stencil_circle() {
+        let image = subject();
+        for flipped in [false, true] {
+            let current = ImageTransform {
+                flipped,
+                ..upright()
+            };
+            let transform =
+                suggest_transform(&image, &dimensions(), FramingPreset::FullBody, &current);
+            assert_eq!(transform.flipped, flipped);
+
+            let (left, top, right, bottom) = drawn_bounds(&image, &transform);
+            let center = SIZE as f32 / 2.0;
+            assert!(((left + right) / 2.0 - center).abs() <= 1.5);
+            assert!(((top + bottom) / 2.0 - center).abs() <= 1.5);
+            // the corners of the subject touch the circle
+            let diagonal = (right - left).hypot(bottom - top);
+            assert!((diagonal - 80.0).abs() <= 3.0, "diagonal {diagonal}");
+        }
+    }
+
+    #[test]
+    fn head_and_shoulders_fill_the_stencil_from_the_top() {
+        let image = subject();
+        let transform = suggest_transform(
+            &image,
+            &dimensions(),
+            FramingPreset::HeadAndShoulders,
+            &upright(),
+        );
+
+        // the upper part of the subject fills most of the circle vertically,
+        // the rest is cut off below it
+        let (left, top, right, _) = drawn_bounds(&image, &transform);
+        let center = SIZE as f32 / 2.0;
+        assert!(((left + right) / 2.0 - center).abs() <= 1.5);
+        assert!((top - (center - FILL * 40.0)).abs() <= 2.0, "top {top}");
+    }
+
+    #[test]
+    fn transparent_images_fill_the_stencil() {
+        let image: SourceImage = DynamicImage::ImageRgba8(RgbaImage::new(50, 25)).into();
+        let transform =
+            suggest_transform(&image, &dimensions(), FramingPreset::FullBody, &upright());
+
+        assert_eq!((transform.pos_x, transform.pos_y), (0.0, 0.0));
+        assert_eq!(transform.scale, 80.0 / 50.0);
+    }
+}
@@ -0,0 +1,373 @@
+use image::{ImageDecoder, Limits, error::LimitErrorKind};
+use serde::{Deserialize, Serialize};
+use tsify::{Ts, Tsify};
+use wasm_bindgen::prelude::*;
+
+use crate::image_options::{ImageDimensions, VectorMask};
+
+/// The number of canvas sized RGBA buffers a render needs at most at the
+/// same time: the mask, the transformed image, the ring layers, the stencils,
+/// the shadows with the buffers of their blur and the two composites that
+/// are blended into the result.
+const RENDER_BUFFERS: u64 = 20;
+
+/// Guardrails against images that would exhaust the wasm memory.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct ProcessingLimits {
+    /// The maximum width of decoded images in pixels.
+    pub max_width: u32,
+    /// The maximum height of decoded images in pixels.
+    pub max_height: u32,
+    /// The maximum number of bytes a decoded image may take up, for animated
+    /// images all frames together. The images a render needs at the same
+    /// time are held to the same limit.
+    pub max_alloc: u64,
+    /// The maximum width and height of the canvas in pixels. Rendering onto
+    /// the canvas has to fit into `max_alloc` as well.
+    pub max_scaled_size: u32,
+    /// The maximum number of shapes in a vector mask.
+    pub max_mask_shapes: u32,
+    /// The maximum number of points of all shapes of a vector mask together.
+    pub max_mask_points: u32,
+}
+
+impl Default for ProcessingLimits {
+    fn default() -> Self {
+        ProcessingLimits {
+            max_width: 16384,
+            max_height: 16384,
+            max_alloc: 1536 * 1024 * 1024,
+            // the canvas of an oversized gargantuan token
+            max_scaled_size: 4096,
+            max_mask_shapes: 1000,
+            max_mask_points: 100_000,
+        }
+    }
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    /// The image is wider or higher than `max_width` or `max_height`.
+    Dimensions,
+    /// The decoded image would take up more than `max_alloc` bytes.
+    Allocation,
+    /// The canvas is larger than `max_scaled_size`.
+    ScaledSize,
+    /// The vector mask has more than `max_mask_shapes` shapes or more than
+    /// `max_mask_points` points.
+    VectorMask,
+}
+
+/// The error that is thrown when an image exceeds one of the
+/// [`ProcessingLimits`]. It is thrown as a plain object, so that the kind of
+/// limit can be told apart.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct LimitError {
+    pub kind: LimitKind,
+    /// The limit that was exceeded.
+    pub limit: u64,
+    /// The value that exceeded the limit, if the decoder reported it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tsify(optional)]
+    pub actual: Option<u64>,
+    pub message: String,
+}
+
+impl From<LimitError> for JsValue {
+    fn from(error: LimitError) -> Self {
+        match Ts::from_rust(&error) {
+            Ok(value) => value.js_value(),
+            Err(_) => JsValue::from_str(&error.message),
+        }
+    }
+}
+
+impl ProcessingLimits {
+    pub fn check_dimensions(&self, width: u32, height: u32) -> Result<(), LimitError> {
+        if width > self.max_width {
+            return Err(LimitError {
+                kind: LimitKind::Dimensions,
+                limit: self.max_width as u64,
+                actual: Some(width as u64),
+                message: format!(
+                    "Image is {width} pixels wide, which exceeds the limit of {} pixels",
+                    self.max_width
+                ),
+            });
+        }
+        if height > self.max_height {
+            return Err(LimitError {
+                kind: LimitKind::Dimensions,
+                limit: self.max_height as u64,
+                actual: Some(height as u64),
+                message: format!(
+                    "Image is {height} pixels high, which exceeds the limit of {} pixels",
+                    self.max_height
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn check_alloc(&self, bytes: u64) -> Result<(), LimitError> {
+        if bytes > self.max_alloc {
+            return Err(LimitError {
+                kind: LimitKind::Allocation,
+                limit: self.max_alloc,
+                actual: Some(bytes),
+                message: format!(
+                    "Decoding the image needs {bytes} bytes, which exceeds the limit of {} bytes",
+                    self.max_alloc
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check the size of the canvas, and that all the images that are
+    /// rendered onto it fit into the allocation limit.
+    pub fn check_canvas(&self, dimensions: &ImageDimensions) -> Result<(), LimitError> {
+        self.check_render(dimensions, 0)
+    }
+
+    /// Like [`Self::check_canvas`], for an animation whose rendered frames
+    /// are all kept until they are encoded.
+    pub fn check_animation(
+        &self,
+        dimensions: &ImageDimensions,
+        frames: usize,
+    ) -> Result<(), LimitError> {
+        self.check_render(dimensions, frames as u64)
+    }
+
+    fn check_render(&self, dimensions: &ImageDimensions, frames: u64) -> Result<(), LimitError> {
+        let size = dimensions.size as u64;
+        if size > self.max_scaled_size as u64 {
+            return Err(LimitError {
+                kind: LimitKind::ScaledSize,
+                limit: self.max_scaled_size as u64,
+                actual: Some(size),
+                message: format!(
+                    "Canvas is {size}x{size} pixels, which exceeds the limit of {} pixels",
+                    self.max_scaled_size
+                ),
+            });
+        }
+
+        let bytes = size * size * 4 * (RENDER_BUFFERS + frames);
+        if bytes > self.max_alloc {
+            return Err(LimitError {
+                kind: LimitKind::Allocation,
+                limit: self.max_alloc,
+                actual: Some(bytes),
+                message: format!(
+                    "Rendering onto a {size}x{size} canvas needs {bytes} bytes, which exceeds the limit of {} bytes",
+                    self.max_alloc
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check the number of shapes of the vector mask, and the number of
+    /// points of all shapes together.
+    pub fn check_vector_mask(&self, vector_mask: &VectorMask) -> Result<(), LimitError> {
+        let shapes = vector_mask.shapes.len() as u64;
+        if shapes > self.max_mask_shapes as u64 {
+            return Err(LimitError {
+                kind: LimitKind::VectorMask,
+                limit: self.max_mask_shapes as u64,
+                actual: Some(shapes),
+                message: format!(
+                    "Vector mask has {shapes} shapes, which exceeds the limit of {}",
+                    self.max_mask_shapes
+                ),
+            });
+        }
+
+        let points: u64 = vector_mask
+            .shapes
+            .iter()
+            .map(|shape| shape.points().len() as u64)
+            .sum();
+        if points > self.max_mask_points as u64 {
+            return Err(LimitError {
+                kind: LimitKind::VectorMask,
+                limit: self.max_mask_points as u64,
+                actual: Some(points),
+                message: format!(
+                    "Vector mask has {points} points, which exceeds the limit of {}",
+                    self.max_mask_points
+                ),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Check the image size of the decoder before decoding, and pass the
+    /// limits on to the decoder for its own allocations.
+    pub fn apply(&self, decoder: &mut impl ImageDecoder) -> Result<(), JsValue> {
+        let (width, height) = decoder.dimensions();
+        self.check_dimensions(width, height)?;
+        self.check_alloc(decoder.total_bytes())?;
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        decoder.set_limits(limits).map_err(|e| self.decode_error(e))
+    }
+
+    /// Turn the limit errors of the image crate into a [`LimitError`].
+    pub fn decode_error(&self, error: image::ImageError) -> JsValue {
+        let image::ImageError::Limits(e) = error else {
+            return JsValue::from_str(&format!("Failed to decode image: {error}"));
+        };
+
+        let (kind, limit) = match e.kind() {
+            LimitErrorKind::DimensionError => (
+                LimitKind::Dimensions,
+                self.max_width.max(self.max_height) as u64,
+            ),
+            LimitErrorKind::InsufficientMemory => (LimitKind::Allocation, self.max_alloc),
+            // the decoder can't enforce the limits, which is no fault of the
+            // image
+            _ => return JsValue::from_str(&format!("Failed to decode image: {e}")),
+        };
+
+        LimitError {
+            kind,
+            limit,
+            actual: None,
+            message: format!("Image exceeds the decoding limits: {e}"),
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_options::MaskShape;
+
+    fn dimensions(size: u32) -> ImageDimensions {
+        ImageDimensions {
+            size,
+            oversized: false,
+            stencil_radius: size / 2,
+        }
+    }
+
+    #[test]
+    fn dimensions_are_checked_per_axis() {
+        let limits = ProcessingLimits {
+            max_width: 100,
+            max_height: 50,
+            ..ProcessingLimits::default()
+        };
+
+        assert!(limits.check_dimensions(100, 50).is_ok());
+
+        let error = limits.check_dimensions(101, 10).unwrap_err();
+        assert_eq!(error.kind, LimitKind::Dimensions);
+        assert_eq!((error.limit, error.actual), (100, Some(101)));
+
+        let error = limits.check_dimensions(10, 51).unwrap_err();
+        assert_eq!((error.limit, error.actual), (50, Some(51)));
+    }
+
+    #[test]
+    fn allocation_is_checked() {
+        let limits = ProcessingLimits {
+            max_alloc: 1000,
+            ..ProcessingLimits::default()
+        };
+
+        assert!(limits.check_alloc(1000).is_ok());
+        let error = limits.check_alloc(1001).unwrap_err();
+        assert_eq!(error.kind, LimitKind::Allocation);
+        assert_eq!(error.actual, Some(1001));
+    }
+
+    #[test]
+    fn canvas_is_checked_against_size_and_allocation() {
+        let limits = ProcessingLimits {
+            max_scaled_size: 512,
+            max_alloc: 256 * 256 * 4 * RENDER_BUFFERS,
+            ..ProcessingLimits::default()
+        };
+
+        assert!(limits.check_canvas(&dimensions(256)).is_ok());
+
+        let error = limits.check_canvas(&dimensions(513)).unwrap_err();
+        assert_eq!(error.kind, LimitKind::ScaledSize);
+        assert_eq!((error.limit, error.actual), (512, Some(513)));
+
+        let error = limits.check_canvas(&dimensions(257)).unwrap_err();
+        assert_eq!(error.kind, LimitKind::Allocation);
+        assert_eq!(error.actual, Some(257 * 257 * 4 * RENDER_BUFFERS));
+    }
+
+    #[test]
+    fn default_limits_fit_the_largest_canvas_into_the_allocation_limit() {
+        let limits = ProcessingLimits::default();
+
+        assert!(limits.check_canvas(&dimensions(4096)).is_ok());
+        let error = limits.check_canvas(&dimensions(11000)).unwrap_err();
+        assert_eq!(error.kind, LimitKind::ScaledSize);
+
+        let unlimited_size = ProcessingLimits {
+            max_scaled_size: u32::MAX,
+            ..limits
+        };
+        let error = unlimited_size.check_canvas(&dimensions(11000)).unwrap_err();
+        assert_eq!(error.kind, LimitKind::Allocation);
+    }
+
+    #[test]
+    fn rendered_animation_frames_are_counted() {
+        let limits = ProcessingLimits {
+            max_alloc: 64 * 64 * 4 * (RENDER_BUFFERS + 10),
+            ..ProcessingLimits::default()
+        };
+
+        assert!(limits.check_animation(&dimensions(64), 10).is_ok());
+        let error = limits.check_animation(&dimensions(64), 11).unwrap_err();
+        assert_eq!(error.kind, LimitKind::Allocation);
+    }
+
+    #[test]
+    fn vector_mask_shapes_and_points_are_counted() {
+        let limits = ProcessingLimits {
+            max_mask_shapes: 2,
+            max_mask_points: 5,
+            ..ProcessingLimits::default()
+        };
+        let polygon = |points: usize| MaskShape::Polygon {
+            points: vec![[0.0, 0.0]; points],
+            erase: false,
+        };
+
+        let mask = VectorMask {
+            shapes: vec![polygon(3), polygon(2)],
+        };
+        assert!(limits.check_vector_mask(&mask).is_ok());
+
+        let mask = VectorMask {
+            shapes: vec![polygon(1), polygon(1), polygon(1)],
+        };
+        let error = limits.check_vector_mask(&mask).unwrap_err();
+        assert_eq!(error.kind, LimitKind::VectorMask);
+        assert_eq!((error.limit, error.actual), (2, Some(3)));
+
+        let mask = VectorMask {
+            shapes: vec![polygon(3), polygon(3)],
+        };
+        let error = limits.check_vector_mask(&mask).unwrap_err();
+        assert_eq!((error.limit, error.actual), (5, Some(6)));
+    }
+}
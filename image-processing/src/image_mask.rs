@@ -0,0 +1,389 @@
+use std::borrow::Cow;
+
+use image::{DynamicImage, GrayImage, ImageBuffer, Rgba, RgbaImage, imageops};
+use tiny_skia::{FillRule, Mask, PathBuilder, Transform};
+use wasm_bindgen::JsValue;
+
+use crate::{
+    decode_limited,
+    image_limits::ProcessingLimits,
+    image_options::{ImageDimensions, ImageRenderOptions, MaskFormat, MaskShape, VectorMask},
+};
+
+/// Decode the mask into an RGBA image of the canvas size, or a blank mask if
+/// there is none.
+pub fn decode_mask(
+    mask_data: Option<Vec<u8>>,
+    options: &ImageRenderOptions,
+    limits: &ProcessingLimits,
+) -> Result<DynamicImage, JsValue> {
+    let size = options.dimensions.size;
+
+    match mask_data {
+        Some(mask_data) => {
+            let format = options.mask_format.unwrap_or(MaskFormat::Rgba {
+                width: size,
+                height: size,
+            });
+            decode_mask_data(mask_data, format, size, limits)
+        }
+        None => Ok(DynamicImage::ImageRgba8(RgbaImage::new(size, size))),
+    }
+}
+
+/// Decode mask data of the given format into an RGBA image of the given
+/// size. Only the alpha of the result matters for the stencil, and masks of
+/// other sizes are resized to the given size.
+pub fn decode_mask_data(
+    mask_data: Vec<u8>,
+    format: MaskFormat,
+    size: u32,
+    limits: &ProcessingLimits,
+) -> Result<DynamicImage, JsValue> {
+    let alpha = match format {
+        MaskFormat::Rgba { width, height } => {
+            check_mask_len(&mask_data, width, height, 4)?;
+            if width == size && height == size {
+                return ImageBuffer::<Rgba<u8>, Vec<u8>>::from_vec(size, size, mask_data)
+                    .map(DynamicImage::ImageRgba8)
+                    .ok_or_else(|| {
+                        JsValue::from_str("Failed to create mask image from provided data")
+                    });
+            }
+
+            GrayImage::from_fn(width, height, |x, y| {
+                let offset = (y as usize * width as usize + x as usize) * 4;
+                image::Luma([mask_data[offset + 3]])
+            })
+        }
+        MaskFormat::Alpha { width, height } => {
+            check_mask_len(&mask_data, width, height, 1)?;
+            GrayImage::from_vec(width, height, mask_data).ok_or_else(|| {
+                JsValue::from_str("Failed to create mask image from provided data")
+            })?
+        }
+        MaskFormat::Encoded => {
+            let mask = decode_limited(&mask_data, limits)?;
+
+            if mask.color().has_alpha() {
+                let mask = mask.into_rgba8();
+                GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+                    image::Luma([mask.get_pixel(x, y)[3]])
+                })
+            } else {
+                mask.into_luma8()
+            }
+        }
+    };
+
+    let alpha = if alpha.width() == size && alpha.height() == size {
+        alpha
+    } else {
+        imageops::resize(&alpha, size, size, imageops::FilterType::Triangle)
+    };
+
+    let mask: RgbaImage = ImageBuffer::from_fn(size, size, |x, y| {
+        Rgba([255, 255, 255, alpha.get_pixel(x, y)[0]])
+    });
+
+    Ok(mask.into())
+}
+
+/// Check that raw mask data has exactly the length its format describes.
+fn check_mask_len(mask_data: &[u8], width: u32, height: u32, channels: u64) -> Result<(), JsValue> {
+    let expected = width as u64 * height as u64 * channels;
+    if width == 0 || height == 0 || mask_data.len() as u64 != expected {
+        return Err(JsValue::from_str(&format!(
+            "Mask has {} bytes, but {width}x{height} pixels with {channels} channels need {expected} bytes",
+            mask_data.len()
+        )));
+    }
+
+    Ok(())
+}
+
+/// Resize the mask to the given dimensions, interpolating the coverage so
+/// that the edges stay smooth.
+pub fn resize_mask(mask: &DynamicImage, dimensions: &ImageDimensions) -> DynamicImage {
+    if mask.width() == dimensions.size && mask.height() == dimensions.size {
+        return mask.clone();
+    }
+
+    mask.resize_exact(
+        dimensions.size,
+        dimensions.size,
+        imageops::FilterType::Triangle,
+    )
+}
+
+/// Soften the edges of the mask, so that they fade over about the given
+/// distance in pixels.
+pub fn feather_mask(mask: &DynamicImage, radius: f32) -> Cow<'_, DynamicImage> {
+    if radius <= 0.0 {
+        return Cow::Borrowed(mask);
+    }
+
+    let mask = mask.to_rgba8();
+    let alpha = GrayImage::from_fn(mask.width(), mask.height(), |x, y| {
+        image::Luma([mask.get_pixel(x, y)[3]])
+    });
+    // most of a gaussian lies within two standard deviations
+    let alpha = imageops::blur(&alpha, radius / 2.0);
+
+    let feathered: RgbaImage = ImageBuffer::from_fn(mask.width(), mask.height(), |x, y| {
+        Rgba([255, 255, 255, alpha.get_pixel(x, y)[0]])
+    });
+    Cow::Owned(feathered.into())
+}
+
+/// The mask with the vector mask of the options drawn onto it, if it is
+/// within the limits.
+pub fn with_vector_mask<'a>(
+    mask: &'a DynamicImage,
+    options: &ImageRenderOptions,
+    limits: &ProcessingLimits,
+) -> Result<Cow<'a, DynamicImage>, JsValue> {
+    match &options.vector_mask {
+        Some(vector_mask) if !vector_mask.shapes.is_empty() => {
+            limits.check_vector_mask(vector_mask)?;
+            let mut mask = mask.to_rgba8();
+            draw_vector_mask(&mut mask, vector_mask);
+            Ok(Cow::Owned(mask.into()))
+        }
+        _ => Ok(Cow::Borrowed(mask)),
+    }
+}
+
+/// Draw the shapes onto the mask with anti-aliased edges, scaled to the size
+/// of the mask. Stroke radii are clamped to half the size of the mask.
+pub fn draw_vector_mask(mask: &mut RgbaImage, vector_mask: &VectorMask) {
+    let size = mask.width();
+
+    for shape in &vector_mask.shapes {
+        let (coverage, erase) = match shape {
+            MaskShape::Polygon { points, erase } => (fill_coverage(points, size, false), *erase),
+            MaskShape::Lasso { points, erase } => (fill_coverage(points, size, true), *erase),
+            MaskShape::Stroke {
+                points,
+                radius,
+                hardness,
+                erase,
+            } => (
+                stroke_coverage(points, radius.min(0.5) * size as f32, *hardness, size),
+                *erase,
+            ),
+        };
+        let Some(coverage) = coverage else {
+            continue;
+        };
+
+        for (pixel, coverage) in mask.pixels_mut().zip(coverage.iter()) {
+            let coverage = *coverage as u32;
+            let alpha = pixel[3] as u32;
+            let alpha = if erase {
+                alpha * (255 - coverage) / 255
+            } else {
+                alpha + coverage * (255 - alpha) / 255
+            };
+            *pixel = Rgba([255, 255, 255, alpha as u8]);
+        }
+    }
+}
+
+/// The coverage of the filled outline. Lassos are smoothed by curving
+/// through the midpoints between the points.
+fn fill_coverage(points: &[[f32; 2]], size: u32, smooth: bool) -> Option<Vec<u8>> {
+    if points.len() < 3 {
+        return None;
+    }
+
+    let mut builder = PathBuilder::new();
+    if smooth {
+        let midpoint = |a: [f32; 2], b: [f32; 2]| [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+        let start = midpoint(points[points.len() - 1], points[0]);
+        builder.move_to(start[0], start[1]);
+        for (i, point) in points.iter().enumerate() {
+            let next = midpoint(*point, points[(i + 1) % points.len()]);
+            builder.quad_to(point[0], point[1], next[0], next[1]);
+        }
+    } else {
+        builder.move_to(points[0][0], points[0][1]);
+        for point in &points[1..] {
+            builder.line_to(point[0], point[1]);
+        }
+    }
+    builder.close();
+
+    let path = builder.finish()?;
+    let mut mask = Mask::new(size, size)?;
+    mask.fill_path(
+        &path,
+        FillRule::Winding,
+        true,
+        Transform::from_scale(size as f32, size as f32),
+    );
+
+    Some(mask.take())
+}
+
+/// The coverage of a round brush moved along the points, in pixels. The
+/// hardness sets how much of the radius is fully covered, the rest fades
+/// out smoothly. Even hard brushes fade over a pixel to smooth the edge.
+fn stroke_coverage(points: &[[f32; 2]], radius: f32, hardness: f32, size: u32) -> Option<Vec<u8>> {
+    if points.is_empty() || radius <= 0.0 {
+        return None;
+    }
+
+    let fade = (radius * (1.0 - hardness.clamp(0.0, 1.0))).max(1.0);
+    let points: Vec<[f32; 2]> = points
+        .iter()
+        .map(|point| [point[0] * size as f32, point[1] * size as f32])
+        .collect();
+    let mut coverage = vec![0u8; size as usize * size as usize];
+
+    // a single point is a dot
+    let segments = points
+        .windows(2)
+        .map(|pair| (pair[0], pair[1]))
+        .chain((points.len() == 1).then_some((points[0], points[0])));
+
+    for (a, b) in segments {
+        let reach = radius + 1.0;
+        let clamp = |value: f32| value.clamp(0.0, size as f32) as u32;
+        let (min_x, max_x) = (clamp(a[0].min(b[0]) - reach), clamp(a[0].max(b[0]) + reach));
+        let (min_y, max_y) = (clamp(a[1].min(b[1]) - reach), clamp(a[1].max(b[1]) + reach));
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let distance = segment_distance([x as f32 + 0.5, y as f32 + 0.5], a, b);
+                let t = ((radius - distance) / fade).clamp(0.0, 1.0);
+                let value = (t * t * (3.0 - 2.0 * t) * 255.0).round() as u8;
+
+                let index = (y * size + x) as usize;
+                coverage[index] = coverage[index].max(value);
+            }
+        }
+    }
+
+    Some(coverage)
+}
+
+/// The distance of the point to the line segment from a to b.
+fn segment_distance(point: [f32; 2], a: [f32; 2], b: [f32; 2]) -> f32 {
+    let (dx, dy) = (b[0] - a[0], b[1] - a[1]);
+    let length = dx * dx + dy * dy;
+    let t = if length > 0.0 {
+        (((point[0] - a[0]) * dx + (point[1] - a[1]) * dy) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+
+    (point[0] - (a[0] + t * dx)).hypot(point[1] - (a[1] + t * dy))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, LumaA};
+
+    use super::*;
+
+    fn alpha(mask: &DynamicImage) -> Vec<u8> {
+        mask.to_rgba8().pixels().map(|pixel| pixel[3]).collect()
+    }
+
+    fn encode(image: DynamicImage) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn rgba_masks_of_the_canvas_size_are_kept() {
+        let data: Vec<u8> = (0..16).collect();
+        let format = MaskFormat::Rgba {
+            width: 2,
+            height: 2,
+        };
+        let mask = decode_mask_data(data.clone(), format, 2, &ProcessingLimits::default()).unwrap();
+
+        assert_eq!(mask.to_rgba8().into_raw(), data);
+    }
+
+    #[test]
+    fn rgba_masks_of_other_sizes_are_resized() {
+        let data = [[0, 0, 0, 255], [0, 0, 0, 0]].repeat(2).concat();
+        let format = MaskFormat::Rgba {
+            width: 2,
+            height: 2,
+        };
+        let mask = decode_mask_data(data, format, 4, &ProcessingLimits::default()).unwrap();
+
+        assert_eq!((mask.width(), mask.height()), (4, 4));
+        let alpha = alpha(&mask);
+        // opaque on the left, transparent on the right, smooth in between
+        assert_eq!(alpha[0], 255);
+        assert_eq!(alpha[3], 0);
+        assert!(alpha[1] > alpha[2] && alpha[2] > 0);
+    }
+
+    #[test]
+    fn alpha_masks_are_used_as_alpha() {
+        let format = MaskFormat::Alpha {
+            width: 2,
+            height: 1,
+        };
+        let mask = decode_mask_data(vec![0, 200], format, 2, &ProcessingLimits::default());
+        let mask = mask.unwrap().to_rgba8();
+
+        assert_eq!(mask.dimensions(), (2, 2));
+        assert_eq!(mask.get_pixel(0, 0).0, [255, 255, 255, 0]);
+        assert_eq!(mask.get_pixel(1, 1).0, [255, 255, 255, 200]);
+    }
+
+    #[test]
+    fn encoded_masks_use_alpha_or_brightness() {
+        let limits = ProcessingLimits::default();
+
+        let with_alpha = ImageBuffer::from_fn(2, 2, |x, _| LumaA([255, x as u8 * 100]));
+        let mask = decode_mask_data(
+            encode(DynamicImage::ImageLumaA8(with_alpha)),
+            MaskFormat::Encoded,
+            2,
+            &limits,
+        );
+        assert_eq!(alpha(&mask.unwrap()), [0, 100, 0, 100]);
+
+        let brightness = GrayImage::from_fn(2, 2, |x, _| image::Luma([x as u8 * 50]));
+        let mask = decode_mask_data(
+            encode(DynamicImage::ImageLuma8(brightness)),
+            MaskFormat::Encoded,
+            2,
+            &limits,
+        );
+        assert_eq!(alpha(&mask.unwrap()), [0, 50, 0, 50]);
+    }
+
+    #[test]
+    fn stroke_radius_is_clamped_to_half_the_mask() {
+        let mut clamped = RgbaImage::new(16, 16);
+        let mut huge = RgbaImage::new(16, 16);
+        let stroke = |radius: f32| VectorMask {
+            shapes: vec![MaskShape::Stroke {
+                points: vec![[0.0, 0.0]],
+                radius,
+                hardness: 1.0,
+                erase: false,
+            }],
+        };
+        draw_vector_mask(&mut clamped, &stroke(0.5));
+        draw_vector_mask(&mut huge, &stroke(1000.0));
+
+        assert_eq!(clamped, huge);
+        // the far corner is out of reach of a dot in the near corner
+        assert_eq!(huge.get_pixel(15, 15)[3], 0);
+    }
+}
@@ -0,0 +1,275 @@
+use std::io::Cursor;
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use image::{
+    DynamicImage, GenericImageView, GrayImage, ImageBuffer, ImageDecoder, ImageReader, Luma,
+};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    image_limits::ProcessingLimits,
+    image_mask::decode_mask_data,
+    image_options::{ImageRenderOptions, MaskFormat},
+};
+
+/// The XML namespace of the settings we embed into XMP packets.
+const XMP_NAMESPACE: &str = "https://www.moritzjung.dev/token-tool/";
+const XMP_SETTINGS_OPEN: &str = "<tokentool:settings>";
+const XMP_SETTINGS_CLOSE: &str = "</tokentool:settings>";
+/// Bump this when the serialized settings change in an incompatible way.
+const SETTINGS_VERSION: u32 = 1;
+
+/// The render settings as they are stored inside an exported image.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SerializedSettings {
+    version: u32,
+    options: ImageRenderOptions,
+    /// The alpha channel of the mask as a base64 encoded grayscale PNG.
+    mask: Option<String>,
+}
+
+/// Render settings that were read back from a previously exported image.
+#[wasm_bindgen]
+pub struct EmbeddedSettings {
+    options: ImageRenderOptions,
+    mask: Option<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl EmbeddedSettings {
+    #[wasm_bindgen(getter)]
+    pub fn options(&self) -> ImageRenderOptions {
+        self.options.clone()
+    }
+
+    /// The mask as raw RGBA data in the size of the stored dimensions, which
+    /// is the mask format the returned options describe.
+    #[wasm_bindgen(getter)]
+    pub fn mask(&self) -> Option<Vec<u8>> {
+        self.mask.clone()
+    }
+}
+
+/// Serialize the render options and mask into an XMP packet.
+pub fn settings_to_xmp(
+    options: &ImageRenderOptions,
+    mask: &DynamicImage,
+) -> Result<String, JsValue> {
+    let settings = SerializedSettings {
+        version: SETTINGS_VERSION,
+        // the mask is handed back as raw RGBA of the canvas size, whatever
+        // format it was rendered from
+        options: ImageRenderOptions {
+            mask_format: None,
+            ..options.clone()
+        },
+        mask: Some(BASE64.encode(encode_mask_png(mask)?)),
+    };
+
+    let json = serde_json::to_string(&settings)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize settings: {e}")))?;
+
+    Ok(format!(
+        concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">"#,
+            r#"<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:tokentool="{}">"#,
+            "{}{}{}",
+            "</rdf:Description>",
+            "</rdf:RDF>",
+            "</x:xmpmeta>",
+        ),
+        XMP_NAMESPACE,
+        XMP_SETTINGS_OPEN,
+        escape_xml(&json),
+        XMP_SETTINGS_CLOSE,
+    ))
+}
+
+/// Read the render settings back from an exported image.
+/// Returns `None` if the image does not contain any settings.
+pub fn read_embedded_settings(
+    image_data: &[u8],
+    limits: &ProcessingLimits,
+) -> Result<Option<EmbeddedSettings>, JsValue> {
+    let mut reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| JsValue::from_str(&format!("Failed to read image: {e}")))?;
+    reader.no_limits();
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| JsValue::from_str(&format!("Failed to decode image: {e}")))?;
+    limits.apply(&mut decoder)?;
+
+    let xmp = decoder
+        .xmp_metadata()
+        .map_err(|e| JsValue::from_str(&format!("Failed to read image metadata: {e}")))?;
+
+    let Some(xmp) = xmp else {
+        return Ok(None);
+    };
+    let xmp = String::from_utf8_lossy(&xmp);
+
+    let Some(start) = xmp.find(XMP_SETTINGS_OPEN) else {
+        return Ok(None);
+    };
+    let start = start + XMP_SETTINGS_OPEN.len();
+    let end = xmp[start..]
+        .find(XMP_SETTINGS_CLOSE)
+        .ok_or_else(|| JsValue::from_str("Embedded settings are incomplete"))?;
+
+    let settings: SerializedSettings =
+        serde_json::from_str(&unescape_xml(&xmp[start..start + end]))
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse embedded settings: {e}")))?;
+
+    if settings.version > SETTINGS_VERSION {
+        return Err(JsValue::from_str(&format!(
+            "Embedded settings version {} is not supported",
+            settings.version
+        )));
+    }
+
+    let mask = settings
+        .mask
+        .map(|mask| {
+            let bytes = BASE64
+                .decode(mask)
+                .map_err(|e| JsValue::from_str(&format!("Failed to decode embedded mask: {e}")))?;
+            decode_mask_png(&bytes, settings.options.dimensions.size, limits)
+        })
+        .transpose()?;
+
+    Ok(Some(EmbeddedSettings {
+        options: settings.options,
+        mask,
+    }))
+}
+
+/// Encode the mask as a grayscale PNG.
+/// Only the alpha channel is stored, since that is all the stencil cares
+/// about.
+pub fn encode_mask_png(mask: &DynamicImage) -> Result<Vec<u8>, JsValue> {
+    let alpha: GrayImage = ImageBuffer::from_fn(mask.width(), mask.height(), |x, y| {
+        Luma([mask.get_pixel(x, y)[3]])
+    });
+
+    let mut bytes = Vec::new();
+    DynamicImage::ImageLuma8(alpha)
+        .write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png)
+        .map_err(|e| JsValue::from_str(&format!("Failed to encode mask: {e}")))?;
+
+    Ok(bytes)
+}
+
+/// Decode a mask stored by [`encode_mask_png`] into raw RGBA data of the given
+/// size.
+pub fn decode_mask_png(
+    mask: &[u8],
+    size: u32,
+    limits: &ProcessingLimits,
+) -> Result<Vec<u8>, JsValue> {
+    limits.check_alloc(size as u64 * size as u64 * 4)?;
+    Ok(
+        decode_mask_data(mask.to_vec(), MaskFormat::Encoded, size, limits)?
+            .into_rgba8()
+            .into_raw(),
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+    use crate::{
+        image_export::image_to_bytes,
+        image_mask::decode_mask,
+        image_options::{ImageExportFormat, ImageExportOptions},
+    };
+
+    fn options() -> ImageRenderOptions {
+        serde_json::from_str(
+            r#"{
+                "transform": { "pos_x": 3.5, "pos_y": -2, "scale": 0.5, "flipped": true },
+                "dimensions": { "size": 4, "oversized": false, "stencil_radius": 2 },
+                "ring": true
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn mask() -> RgbaImage {
+        RgbaImage::from_fn(4, 4, |x, y| Rgba([255, 255, 255, (x * 60 + y) as u8]))
+    }
+
+    fn export(format: ImageExportFormat, xmp: Option<&str>) -> Vec<u8> {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(4, 4, Rgba([9, 8, 7, 255])));
+        let options = ImageExportOptions {
+            format,
+            ..ImageExportOptions::default()
+        };
+
+        image_to_bytes(&image, &options, xmp).unwrap()
+    }
+
+    #[test]
+    fn settings_round_trip_through_png_and_webp() {
+        let options = options();
+        let xmp = settings_to_xmp(&options, &DynamicImage::ImageRgba8(mask())).unwrap();
+
+        for format in [ImageExportFormat::Png, ImageExportFormat::WebpLossless] {
+            let bytes = export(format, Some(&xmp));
+            let settings = read_embedded_settings(&bytes, &ProcessingLimits::default())
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(
+                serde_json::to_value(settings.options()).unwrap(),
+                serde_json::to_value(&options).unwrap()
+            );
+            assert_eq!(settings.mask(), Some(mask().into_raw()));
+        }
+    }
+
+    #[test]
+    fn read_back_masks_fit_the_read_back_options() {
+        let limits = ProcessingLimits::default();
+        let options = ImageRenderOptions {
+            mask_format: Some(MaskFormat::Encoded),
+            ..options()
+        };
+        let xmp = settings_to_xmp(&options, &DynamicImage::ImageRgba8(mask())).unwrap();
+        let bytes = export(ImageExportFormat::Png, Some(&xmp));
+        let settings = read_embedded_settings(&bytes, &limits).unwrap().unwrap();
+
+        assert_eq!(settings.options().mask_format, None);
+        let read_back = decode_mask(settings.mask(), &settings.options(), &limits).unwrap();
+        assert_eq!(read_back.into_rgba8(), mask());
+    }
+
+    #[test]
+    fn images_without_settings_have_none() {
+        let bytes = export(ImageExportFormat::Png, None);
+
+        assert!(
+            read_embedded_settings(&bytes, &ProcessingLimits::default())
+                .unwrap()
+                .is_none()
+        );
+    }
+}
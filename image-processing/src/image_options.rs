@@ -1,3 +1,4 @@
+use image::{imageops::FilterType, metadata::Orientation};
 use serde::{Deserialize, Serialize};
 use tsify::Tsify;
 use wasm_bindgen::prelude::*;
@@ -5,10 +6,131 @@ use wasm_bindgen::prelude::*;
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct ImageTransform {
-    pub pos_x: i32,
-    pub pos_y: i32,
+    /// Offset of the image centre from the canvas centre in pixels.
+    pub pos_x: f32,
+    pub pos_y: f32,
     pub scale: f32,
     pub flipped: bool,
+    /// Clockwise rotation around the image centre in degrees.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub rotation: f32,
+    /// Only draw this part of the source image. The crop is taken from the
+    /// upright image before it is scaled, and the centre of the crop takes
+    /// the place of the image centre.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub crop: Option<SourceCrop>,
+}
+
+impl ImageTransform {
+    /// Scale the transform by the given factor, e.g. when the output
+    /// dimensions change.
+    pub fn scaled(&self, factor: f32) -> ImageTransform {
+        ImageTransform {
+            pos_x: self.pos_x * factor,
+            pos_y: self.pos_y * factor,
+            scale: self.scale * factor,
+            flipped: self.flipped,
+            rotation: self.rotation,
+            crop: self.crop,
+        }
+    }
+
+    /// The transform relative to the canvas size and the size of the
+    /// upright source image.
+    pub fn normalized(&self, canvas_size: u32, source_size: (u32, u32)) -> NormalizedTransform {
+        let canvas_size = canvas_size.max(1) as f32;
+        let (width, height) = (source_size.0.max(1) as f32, source_size.1.max(1) as f32);
+
+        NormalizedTransform {
+            pos_x: self.pos_x / canvas_size,
+            pos_y: self.pos_y / canvas_size,
+            scale: self.scale * width.max(height) / canvas_size,
+            flipped: self.flipped,
+            rotation: self.rotation,
+            crop: self.crop.map(|crop| SourceCrop {
+                x: crop.x / width,
+                y: crop.y / height,
+                width: crop.width / width,
+                height: crop.height / height,
+            }),
+        }
+    }
+}
+
+/// An [`ImageTransform`] relative to the canvas and source size, so that it
+/// gives the same framing at any output size and source resolution.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct NormalizedTransform {
+    /// Offset of the image centre from the canvas centre, as a fraction of
+    /// the canvas size.
+    pub pos_x: f32,
+    pub pos_y: f32,
+    /// The size of the longer side of the upright source image as a
+    /// fraction of the canvas size, 1 means it spans the whole canvas.
+    pub scale: f32,
+    pub flipped: bool,
+    /// Clockwise rotation around the image centre in degrees.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub rotation: f32,
+    /// The crop as fractions of the width and height of the upright source
+    /// image.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub crop: Option<SourceCrop>,
+}
+
+impl NormalizedTransform {
+    /// The transform for the given canvas size and size of the upright
+    /// source image.
+    pub fn to_transform(&self, canvas_size: u32, source_size: (u32, u32)) -> ImageTransform {
+        let canvas_size = canvas_size.max(1) as f32;
+        let (width, height) = (source_size.0.max(1) as f32, source_size.1.max(1) as f32);
+
+        ImageTransform {
+            pos_x: self.pos_x * canvas_size,
+            pos_y: self.pos_y * canvas_size,
+            scale: self.scale * canvas_size / width.max(height),
+            flipped: self.flipped,
+            rotation: self.rotation,
+            crop: self.crop.map(|crop| SourceCrop {
+                x: crop.x * width,
+                y: crop.y * height,
+                width: crop.width * width,
+                height: crop.height * height,
+            }),
+        }
+    }
+}
+
+/// A rectangle of the upright source image in source pixels.
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SourceCrop {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl SourceCrop {
+    /// The part of the crop that lies within an upright image of the given
+    /// size. A crop may reach past the edges of the image, which only adds
+    /// transparent space around it.
+    pub fn clamped(&self, width: f32, height: f32) -> SourceCrop {
+        let left = self.x.clamp(0.0, width);
+        let top = self.y.clamp(0.0, height);
+        let right = (self.x + self.width).clamp(left, width);
+        let bottom = (self.y + self.height).clamp(top, height);
+
+        SourceCrop {
+            x: left,
+            y: top,
+            width: right - left,
+            height: bottom - top,
+        }
+    }
 }
 
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
@@ -43,6 +165,23 @@ impl ImageDimensions {
             self.size
         }
     }
+
+    /// Scale the dimensions so that the token has the given size, keeping the
+    /// stencil radius proportional.
+    pub fn with_token_size(&self, token_size: u32) -> ImageDimensions {
+        let size = if self.oversized {
+            token_size * 2
+        } else {
+            token_size
+        };
+        let factor = size as f32 / self.size as f32;
+
+        ImageDimensions {
+            size,
+            oversized: self.oversized,
+            stencil_radius: (self.stencil_radius as f32 * factor).round() as u32,
+        }
+    }
 }
 
 #[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
@@ -51,4 +190,466 @@ pub struct ImageRenderOptions {
     pub transform: ImageTransform,
     pub dimensions: ImageDimensions,
     pub ring: bool,
-}
\ No newline at end of file
+    /// Only render the subject without the ring and the ring shadow, for use
+    /// with Foundry's dynamic token ring, which draws the ring itself.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub subject_only: bool,
+    /// Preview quality renders at a reduced resolution with cheaper filters,
+    /// for interactive editing. Defaults to full quality.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub quality: RenderQuality,
+    /// The output format, defaults to lossless WebP.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub export: Option<ImageExportOptions>,
+    /// Overrides the orientation stored in the EXIF data of the image.
+    /// The EXIF orientation is used if this is not set.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub orientation: Option<ImageOrientation>,
+    /// The filter the source image and the border are resampled with, in
+    /// previews too. Defaults to the filter of the render quality.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub filter: Option<ResampleFilter>,
+    /// Keep pixel art crisp: the source is scaled by whole numbers and
+    /// rotated by multiples of 90 degrees with nearest-neighbour sampling,
+    /// and shadows are not blurred.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub pixel_art: bool,
+    /// How the raster mask is stored, defaults to raw RGBA data of the
+    /// canvas size.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub mask_format: Option<MaskFormat>,
+    /// Shapes that are drawn onto the mask, after the raster mask.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub vector_mask: Option<VectorMask>,
+    /// The distance in pixels over which the edges of the mask fade, so that
+    /// popped out parts blend into the content inside the ring.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub mask_feather: f32,
+    /// The grid target of the ring frame to render with, as in the sprite
+    /// sheet of the ring. By default the smallest ring frame at least as
+    /// large as the token is used.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub grid_target: Option<u32>,
+}
+
+impl ImageRenderOptions {
+    /// The filter to resample with: nearest-neighbour for pixel art,
+    /// otherwise the chosen filter, falling back to the filter of the render
+    /// quality.
+    pub fn filter(&self) -> FilterType {
+        if self.pixel_art {
+            return FilterType::Nearest;
+        }
+
+        self.filter.map(Into::into).unwrap_or(self.quality.filter())
+    }
+
+    /// Scale the dimensions and transform so that the token has the given
+    /// size.
+    pub fn with_token_size(&self, token_size: u32) -> ImageRenderOptions {
+        let dimensions = self.dimensions.with_token_size(token_size);
+        let factor = dimensions.size as f32 / self.dimensions.size as f32;
+
+        ImageRenderOptions {
+            transform: self.transform.scaled(factor),
+            dimensions,
+            mask_feather: self.mask_feather * factor,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderQuality {
+    #[default]
+    Full,
+    Preview,
+}
+
+impl RenderQuality {
+    /// The factor the resolution is reduced by while rendering.
+    pub fn resolution_factor(&self) -> f32 {
+        match self {
+            RenderQuality::Full => 1.0,
+            RenderQuality::Preview => 0.5,
+        }
+    }
+
+    pub fn filter(&self) -> FilterType {
+        match self {
+            RenderQuality::Full => FilterType::CatmullRom,
+            RenderQuality::Preview => FilterType::Triangle,
+        }
+    }
+}
+
+/// How the raster mask is stored. Only the alpha of the mask is used, masks
+/// that are not of the canvas size are resized to it.
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "type")]
+pub enum MaskFormat {
+    /// Raw RGBA data.
+    Rgba { width: u32, height: u32 },
+    /// Raw 8-bit alpha, one byte per pixel.
+    Alpha { width: u32, height: u32 },
+    /// An encoded image like a PNG. The alpha channel is used, or the
+    /// brightness if the image has no alpha.
+    Encoded,
+}
+
+/// A mask made of shapes, which stays sharp at any size and can be stored
+/// as JSON. Coordinates and sizes are fractions of the canvas size. The
+/// shapes are drawn in order, so later shapes can erase earlier ones.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct VectorMask {
+    pub shapes: Vec<MaskShape>,
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub enum MaskShape {
+    /// A polygon with straight edges.
+    Polygon {
+        points: Vec<[f32; 2]>,
+        /// Remove the shape from the mask instead of adding it.
+        #[serde(default)]
+        #[tsify(optional)]
+        erase: bool,
+    },
+    /// A freehand lasso path, which is smoothed and closed.
+    Lasso {
+        points: Vec<[f32; 2]>,
+        #[serde(default)]
+        #[tsify(optional)]
+        erase: bool,
+    },
+    /// A brush stroke along the points.
+    Stroke {
+        points: Vec<[f32; 2]>,
+        /// At most half of the canvas size, larger radii are clamped.
+        radius: f32,
+        /// 1 gives a hard edge, 0 fades out all the way from the centre.
+        hardness: f32,
+        #[serde(default)]
+        #[tsify(optional)]
+        erase: bool,
+    },
+}
+
+impl MaskShape {
+    pub fn points(&self) -> &[[f32; 2]] {
+        match self {
+            MaskShape::Polygon { points, .. }
+            | MaskShape::Lasso { points, .. }
+            | MaskShape::Stroke { points, .. } => points,
+        }
+    }
+}
+
+/// How much of the subject a suggested transform fits into the stencil
+/// circle, measured from the top of the subject.
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FramingPreset {
+    /// The head and shoulders, the upper 30% of the subject.
+    HeadAndShoulders,
+    /// The upper half of the subject.
+    Bust,
+    /// The whole subject.
+    #[default]
+    FullBody,
+}
+
+impl FramingPreset {
+    /// The part of the subject height that is framed.
+    pub fn height_fraction(&self) -> f32 {
+        match self {
+            FramingPreset::HeadAndShoulders => 0.3,
+            FramingPreset::Bust => 0.5,
+            FramingPreset::FullBody => 1.0,
+        }
+    }
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResampleFilter {
+    /// Nearest neighbour, keeps hard pixel edges. The source is scaled by
+    /// whole numbers and rotated by multiples of 90 degrees with it.
+    Nearest,
+    /// Linear interpolation, fast but soft.
+    Triangle,
+    /// Cubic interpolation, the default for full quality renders.
+    CatmullRom,
+    /// Gaussian, very smooth without ringing.
+    Gaussian,
+    /// Lanczos with a window of 3, the sharpest, suited for photographic
+    /// art.
+    Lanczos3,
+}
+
+impl From<ResampleFilter> for FilterType {
+    fn from(filter: ResampleFilter) -> Self {
+        match filter {
+            ResampleFilter::Nearest => FilterType::Nearest,
+            ResampleFilter::Triangle => FilterType::Triangle,
+            ResampleFilter::CatmullRom => FilterType::CatmullRom,
+            ResampleFilter::Gaussian => FilterType::Gaussian,
+            ResampleFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// How the stored pixels have to be transformed to display the image
+/// upright, as described by the EXIF orientation tag.
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageOrientation {
+    #[default]
+    NoTransforms,
+    /// Rotate by 90 degrees clockwise.
+    Rotate90,
+    Rotate180,
+    /// Rotate by 270 degrees clockwise.
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    /// Rotate by 90 degrees clockwise, then flip horizontally.
+    Rotate90FlipH,
+    /// Rotate by 270 degrees clockwise, then flip horizontally.
+    Rotate270FlipH,
+}
+
+impl From<Orientation> for ImageOrientation {
+    fn from(orientation: Orientation) -> Self {
+        match orientation {
+            Orientation::NoTransforms => ImageOrientation::NoTransforms,
+            Orientation::Rotate90 => ImageOrientation::Rotate90,
+            Orientation::Rotate180 => ImageOrientation::Rotate180,
+            Orientation::Rotate270 => ImageOrientation::Rotate270,
+            Orientation::FlipHorizontal => ImageOrientation::FlipHorizontal,
+            Orientation::FlipVertical => ImageOrientation::FlipVertical,
+            Orientation::Rotate90FlipH => ImageOrientation::Rotate90FlipH,
+            Orientation::Rotate270FlipH => ImageOrientation::Rotate270FlipH,
+        }
+    }
+}
+
+impl From<ImageOrientation> for Orientation {
+    fn from(orientation: ImageOrientation) -> Self {
+        match orientation {
+            ImageOrientation::NoTransforms => Orientation::NoTransforms,
+            ImageOrientation::Rotate90 => Orientation::Rotate90,
+            ImageOrientation::Rotate180 => Orientation::Rotate180,
+            ImageOrientation::Rotate270 => Orientation::Rotate270,
+            ImageOrientation::FlipHorizontal => Orientation::FlipHorizontal,
+            ImageOrientation::FlipVertical => Orientation::FlipVertical,
+            ImageOrientation::Rotate90FlipH => Orientation::Rotate90FlipH,
+            ImageOrientation::Rotate270FlipH => Orientation::Rotate270FlipH,
+        }
+    }
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum ImageExportFormat {
+    Png,
+    WebpLossless,
+    /// Lossless WebP of an image with reduced colour precision.
+    /// Quality between 0 and 100, where 100 is lossless.
+    ///
+    /// This is not lossy (VP8) WebP: the WebP encoder available to the wasm
+    /// build only writes lossless images, so lower qualities shrink the file
+    /// far less than a lossy encoder would.
+    WebpNearLossless { quality: u8 },
+    /// Quality between 0 and 100, where 100 is the best quality.
+    Avif { quality: u8 },
+}
+
+impl ImageExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageExportFormat::Png => "png",
+            ImageExportFormat::WebpLossless | ImageExportFormat::WebpNearLossless { .. } => "webp",
+            ImageExportFormat::Avif { .. } => "avif",
+        }
+    }
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct ImageExportOptions {
+    pub format: ImageExportFormat,
+    /// Compression level between 0 and 9.
+    /// Higher values produce smaller files but take longer to encode.
+    /// WebP only distinguishes 0 (fastest) from the other levels.
+    pub compression: u8,
+    /// Embed the render options and mask into the image, so that they can be
+    /// read back later. Only supported for PNG and WebP.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub embed_settings: bool,
+    /// Tag the image as sRGB. Only supported for PNG and WebP, AVIF is
+    /// always tagged as sRGB.
+    #[serde(default)]
+    #[tsify(optional)]
+    pub srgb_profile: bool,
+}
+
+impl Default for ImageExportOptions {
+    fn default() -> Self {
+        ImageExportOptions {
+            format: ImageExportFormat::WebpLossless,
+            compression: 6,
+            embed_settings: false,
+            srgb_profile: false,
+        }
+    }
+}
+
+/// A single file in a ZIP export.
+/// The file extension is added based on the export format.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct ZipExportEntry {
+    pub name: String,
+    pub options: ImageRenderOptions,
+}
+
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct ZipExportOptions {
+    pub entries: Vec<ZipExportEntry>,
+}
+
+/// The values for `prototypeToken.ring.subject` that match a subject only
+/// render.
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct RingSubject {
+    pub scale: f32,
+    pub texture: Option<String>,
+}
+
+/// What a layer of an imported PSD is used for.
+#[cfg(feature = "psd")]
+#[derive(Tsify, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PsdLayerRole {
+    /// The layer is part of the token image.
+    #[default]
+    Subject,
+    /// The layer is part of the token image and its alpha is added to the
+    /// mask, so that it pops out of the ring.
+    PopOut,
+    /// The layer is ignored.
+    Discard,
+}
+
+#[cfg(feature = "psd")]
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct PsdImportOptions {
+    /// The role of every layer, in the same order as the layers returned by
+    /// `psd_layers`. Layers without a role are used as subject if they are
+    /// visible and discarded if they are hidden.
+    pub roles: Vec<PsdLayerRole>,
+}
+
+/// A layer of a PSD file, index 0 is the bottom layer.
+#[cfg(feature = "psd")]
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct PsdLayerInfo {
+    pub name: String,
+    pub visible: bool,
+}
+
+#[cfg(feature = "psd")]
+#[derive(Tsify, Serialize, Deserialize, Clone, Debug)]
+pub struct PsdLayers {
+    pub layers: Vec<PsdLayerInfo>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{a} != {b}");
+    }
+
+    fn transform() -> ImageTransform {
+        ImageTransform {
+            pos_x: 12.0,
+            pos_y: -30.0,
+            scale: 1.5,
+            flipped: true,
+            rotation: 45.0,
+            crop: Some(SourceCrop {
+                x: 10.0,
+                y: 20.0,
+                width: 150.0,
+                height: 100.0,
+            }),
+        }
+    }
+
+    #[test]
+    fn normalized_transform_round_trips() {
+        let original = transform();
+        let transform = original
+            .normalized(512, (300, 200))
+            .to_transform(512, (300, 200));
+
+        assert_close(transform.pos_x, original.pos_x);
+        assert_close(transform.pos_y, original.pos_y);
+        assert_close(transform.scale, original.scale);
+        assert_eq!(transform.flipped, original.flipped);
+        assert_close(transform.rotation, original.rotation);
+
+        let (crop, original_crop) = (transform.crop.unwrap(), original.crop.unwrap());
+        assert_close(crop.x, original_crop.x);
+        assert_close(crop.y, original_crop.y);
+        assert_close(crop.width, original_crop.width);
+        assert_close(crop.height, original_crop.height);
+    }
+
+    #[test]
+    fn normalized_transform_keeps_the_framing_at_other_sizes() {
+        let original = transform();
+        // twice the canvas size and twice the source resolution
+        let transform = original
+            .normalized(512, (300, 200))
+            .to_transform(1024, (600, 400));
+
+        assert_close(transform.pos_x, original.pos_x * 2.0);
+        assert_close(transform.pos_y, original.pos_y * 2.0);
+        // the source has twice the pixels, so it is scaled the same
+        assert_close(transform.scale, original.scale);
+        assert_close(transform.crop.unwrap().x, original.crop.unwrap().x * 2.0);
+    }
+
+    #[test]
+    fn crop_is_clamped_to_the_image() {
+        let crop = SourceCrop {
+            x: -10.0,
+            y: 50.0,
+            width: 40.0,
+            height: 100.0,
+        };
+
+        assert_eq!(
+            crop.clamped(100.0, 80.0),
+            SourceCrop {
+                x: 0.0,
+                y: 50.0,
+                width: 30.0,
+                height: 30.0,
+            }
+        );
+
+        let outside = SourceCrop { x: 200.0, ..crop };
+        let clamped = outside.clamped(100.0, 80.0);
+        assert_eq!((clamped.width, clamped.height), (0.0, 30.0));
+    }
+}
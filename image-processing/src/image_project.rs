@@ -0,0 +1,272 @@
+use std::io::{Cursor, Read};
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use zip::ZipArchive;
+
+use crate::{
+    image_export::write_zip,
+    image_limits::ProcessingLimits,
+    image_metadata::{decode_mask_png, encode_mask_png},
+    image_options::ImageRenderOptions,
+};
+
+const PROJECT_FILE_NAME: &str = "project.json";
+const SOURCE_FILE_NAME: &str = "source";
+const MASK_FILE_NAME: &str = "mask.png";
+const RING_IMAGE_FILE_NAME: &str = "ring/sprite-sheet";
+const RING_CONFIG_FILE_NAME: &str = "ring/config.json";
+/// Bump this when the project format changes in an incompatible way.
+const PROJECT_VERSION: u32 = 1;
+
+/// The `project.json` inside a project file.
+/// The source image, mask and ring are stored as separate files next to it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ProjectManifest {
+    version: u32,
+    options: ImageRenderOptions,
+    has_mask: bool,
+    has_ring: bool,
+}
+
+/// A token editing session that was loaded from a project file.
+#[wasm_bindgen]
+pub struct TokenProject {
+    image: Vec<u8>,
+    mask: Option<Vec<u8>>,
+    options: ImageRenderOptions,
+    ring_image: Option<Vec<u8>>,
+    ring_config: Option<String>,
+}
+
+#[wasm_bindgen]
+impl TokenProject {
+    /// The source image as it was originally loaded.
+    #[wasm_bindgen(getter)]
+    pub fn image(&self) -> Vec<u8> {
+        self.image.clone()
+    }
+
+    /// The mask as raw RGBA data in the size of the stored dimensions, which
+    /// is the mask format the returned options describe.
+    #[wasm_bindgen(getter)]
+    pub fn mask(&self) -> Option<Vec<u8>> {
+        self.mask.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn options(&self) -> ImageRenderOptions {
+        self.options.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ring_image(&self) -> Option<Vec<u8>> {
+        self.ring_image.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn ring_config(&self) -> Option<String> {
+        self.ring_config.clone()
+    }
+}
+
+impl TokenProject {
+    /// The ring sprite sheet and config, if the project contains a ring.
+    pub fn get_ring(&self) -> Option<(&[u8], &str)> {
+        match (&self.ring_image, &self.ring_config) {
+            (Some(image), Some(config)) => Some((image, config)),
+            _ => None,
+        }
+    }
+}
+
+/// Write the source image, mask, render options and optionally the ring into
+/// a single project file.
+pub fn save_project(
+    image_data: &[u8],
+    mask: Option<&DynamicImage>,
+    options: &ImageRenderOptions,
+    ring: Option<(&[u8], &str)>,
+) -> Result<Vec<u8>, JsValue> {
+    let manifest = ProjectManifest {
+        version: PROJECT_VERSION,
+        // the mask is stored as a PNG and loaded as raw RGBA of the canvas
+        // size, which is the default mask format
+        options: ImageRenderOptions {
+            mask_format: None,
+            ..options.clone()
+        },
+        has_mask: mask.is_some(),
+        has_ring: ring.is_some(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize project: {e}")))?;
+    let mask = mask.map(encode_mask_png).transpose()?;
+
+    let mut files: Vec<(&str, &[u8])> = vec![
+        (PROJECT_FILE_NAME, &manifest),
+        (SOURCE_FILE_NAME, image_data),
+    ];
+    if let Some(mask) = &mask {
+        files.push((MASK_FILE_NAME, mask));
+    }
+    if let Some((ring_image, ring_config)) = ring {
+        files.push((RING_IMAGE_FILE_NAME, ring_image));
+        files.push((RING_CONFIG_FILE_NAME, ring_config.as_bytes()));
+    }
+
+    write_zip(&files)
+}
+
+/// Read a project file written by [`save_project`].
+/// No file inside the project may be larger than the allocation limit.
+pub fn load_project(
+    project_data: &[u8],
+    limits: &ProcessingLimits,
+) -> Result<TokenProject, JsValue> {
+    let mut archive = ZipArchive::new(Cursor::new(project_data))
+        .map_err(|e| JsValue::from_str(&format!("Failed to read project: {e}")))?;
+
+    let manifest: ProjectManifest =
+        serde_json::from_slice(&read_file(&mut archive, PROJECT_FILE_NAME, limits)?)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse project: {e}")))?;
+
+    if manifest.version > PROJECT_VERSION {
+        return Err(JsValue::from_str(&format!(
+            "Project version {} is not supported",
+            manifest.version
+        )));
+    }
+
+    let image = read_file(&mut archive, SOURCE_FILE_NAME, limits)?;
+    let mask = if manifest.has_mask {
+        let mask = read_file(&mut archive, MASK_FILE_NAME, limits)?;
+        Some(decode_mask_png(
+            &mask,
+            manifest.options.dimensions.size,
+            limits,
+        )?)
+    } else {
+        None
+    };
+    let (ring_image, ring_config) = if manifest.has_ring {
+        let ring_config =
+            String::from_utf8(read_file(&mut archive, RING_CONFIG_FILE_NAME, limits)?)
+                .map_err(|e| JsValue::from_str(&format!("Failed to read ring config: {e}")))?;
+        (
+            Some(read_file(&mut archive, RING_IMAGE_FILE_NAME, limits)?),
+            Some(ring_config),
+        )
+    } else {
+        (None, None)
+    };
+
+    Ok(TokenProject {
+        image,
+        mask,
+        options: manifest.options,
+        ring_image,
+        ring_config,
+    })
+}
+
+fn read_file(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+    limits: &ProcessingLimits,
+) -> Result<Vec<u8>, JsValue> {
+    let file = archive
+        .by_name(name)
+        .map_err(|e| JsValue::from_str(&format!("Failed to find {name} in project: {e}")))?;
+    limits.check_alloc(file.size())?;
+
+    // the size in the archive can't be trusted, so the read is cut off one
+    // byte after the limit to notice files that are larger than they claim
+    let mut bytes = Vec::with_capacity(file.size() as usize);
+    file.take(limits.max_alloc.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .map_err(|e| JsValue::from_str(&format!("Failed to read {name} from project: {e}")))?;
+    limits.check_alloc(bytes.len() as u64)?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ImageFormat, Rgba, RgbaImage};
+
+    use super::*;
+    use crate::{ImageProcessor, image_mask::decode_mask, image_options::MaskFormat};
+
+    fn options() -> ImageRenderOptions {
+        serde_json::from_str(
+            r#"{
+                "transform": { "pos_x": 1, "pos_y": 0.5, "scale": 2, "flipped": false },
+                "dimensions": { "size": 4, "oversized": false, "stencil_radius": 2 },
+                "ring": false
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn mask() -> RgbaImage {
+        RgbaImage::from_fn(4, 4, |x, y| Rgba([255, 255, 255, (x * 16 + y * 50) as u8]))
+    }
+
+    #[test]
+    fn projects_round_trip() {
+        let options = options();
+        let mask = DynamicImage::ImageRgba8(mask());
+        let ring = (&b"ring image"[..], r#"{"config":{}}"#);
+
+        let bytes = save_project(b"source image", Some(&mask), &options, Some(ring)).unwrap();
+        let project = load_project(&bytes, &ProcessingLimits::default()).unwrap();
+
+        assert_eq!(project.image(), b"source image");
+        assert_eq!(project.mask(), Some(mask.into_rgba8().into_raw()));
+        assert_eq!(project.get_ring(), Some(ring));
+        assert_eq!(
+            serde_json::to_value(project.options()).unwrap(),
+            serde_json::to_value(&options).unwrap()
+        );
+    }
+
+    #[test]
+    fn loaded_projects_render_with_their_mask() {
+        let limits = ProcessingLimits::default();
+        let options = ImageRenderOptions {
+            mask_format: Some(MaskFormat::Alpha {
+                width: 2,
+                height: 2,
+            }),
+            ..options()
+        };
+        let mut source = Vec::new();
+        DynamicImage::ImageRgba8(mask())
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Png)
+            .unwrap();
+
+        let mask = decode_mask(Some(vec![0, 255, 255, 0]), &options, &limits).unwrap();
+
+        let bytes = save_project(&source, Some(&mask), &options, None).unwrap();
+        let project = load_project(&bytes, &limits).unwrap();
+
+        assert_eq!(project.options().mask_format, None);
+        let processor = ImageProcessor::new().unwrap();
+        assert!(
+            processor
+                .render(&project.image(), project.mask(), project.options())
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn projects_without_mask_and_ring_round_trip() {
+        let bytes = save_project(b"source image", None, &options(), None).unwrap();
+        let project = load_project(&bytes, &ProcessingLimits::default()).unwrap();
+
+        assert_eq!(project.mask(), None);
+        assert_eq!(project.get_ring(), None);
+    }
+}
@@ -0,0 +1,160 @@
+use image::{DynamicImage, ImageBuffer, Rgba, RgbaImage, imageops};
+use psd::{Psd, PsdLayer};
+use wasm_bindgen::JsValue;
+
+use crate::{
+    image_limits::ProcessingLimits,
+    image_options::{PsdImportOptions, PsdLayerInfo, PsdLayerRole, PsdLayers},
+};
+
+/// The PSD file header, which ends with the height and width.
+const PSD_HEADER_LEN: usize = 26;
+
+/// The images of the size of the PSD that are kept besides the layers while
+/// importing: the merged image of the file, the layer that is being added,
+/// the flattened image, the pop-out layers and the mask made from them.
+const IMPORT_IMAGES: u64 = 5;
+
+/// A PSD that was flattened according to the layer roles.
+pub struct PsdImport {
+    /// All subject and pop-out layers.
+    pub image: DynamicImage,
+    /// The alpha of all pop-out layers, in the same space as the image.
+    /// `None` if no layer is used as pop-out.
+    pub mask: Option<DynamicImage>,
+}
+
+/// List the layers of a PSD, so that they can be assigned roles.
+pub fn read_psd_layers(psd_data: &[u8], limits: &ProcessingLimits) -> Result<PsdLayers, JsValue> {
+    check_limits(psd_data, limits)?;
+    let psd = parse_psd(psd_data)?;
+
+    Ok(PsdLayers {
+        layers: psd
+            .layers()
+            .iter()
+            .map(|layer| PsdLayerInfo {
+                name: layer.name().to_string(),
+                visible: is_visible(layer),
+            })
+            .collect(),
+    })
+}
+
+/// Flatten the layers of a PSD into the source image and a pop-out mask.
+pub fn import_psd(
+    psd_data: &[u8],
+    options: &PsdImportOptions,
+    limits: &ProcessingLimits,
+) -> Result<PsdImport, JsValue> {
+    check_limits(psd_data, limits)?;
+    let psd = parse_psd(psd_data)?;
+
+    // a PSD without a layer section only has the merged image
+    if psd.layers().is_empty() {
+        return Ok(PsdImport {
+            image: to_image(&psd, psd.rgba())?,
+            mask: None,
+        });
+    }
+
+    let mut image = RgbaImage::new(psd.width(), psd.height());
+    let mut pop_out = None;
+
+    // layers are stored bottom to top
+    for (idx, layer) in psd.layers().iter().enumerate() {
+        let role = match options.roles.get(idx) {
+            Some(role) => *role,
+            None if is_visible(layer) => PsdLayerRole::Subject,
+            None => PsdLayerRole::Discard,
+        };
+        if role == PsdLayerRole::Discard || layer.opacity() == 0 {
+            continue;
+        }
+
+        let mut layer_image = to_image(&psd, layer.rgba())?.into_rgba8();
+        if layer.opacity() < 255 {
+            for pixel in layer_image.pixels_mut() {
+                pixel[3] = (pixel[3] as u32 * layer.opacity() as u32 / 255) as u8;
+            }
+        }
+
+        imageops::overlay(&mut image, &layer_image, 0, 0);
+        if role == PsdLayerRole::PopOut {
+            let mask = pop_out.get_or_insert_with(|| RgbaImage::new(psd.width(), psd.height()));
+            imageops::overlay(mask, &layer_image, 0, 0);
+        }
+    }
+
+    // the stencil only looks at the alpha channel
+    let mask = pop_out.map(|pop_out| {
+        let mask: RgbaImage = ImageBuffer::from_fn(pop_out.width(), pop_out.height(), |x, y| {
+            Rgba([255, 255, 255, pop_out.get_pixel(x, y)[3]])
+        });
+        DynamicImage::ImageRgba8(mask)
+    });
+
+    Ok(PsdImport {
+        image: DynamicImage::ImageRgba8(image),
+        mask,
+    })
+}
+
+/// The psd crate reads the visibility flag inverted, Photoshop sets the bit
+/// for hidden layers.
+fn is_visible(layer: &PsdLayer) -> bool {
+    !layer.visible()
+}
+
+/// Check the size and the number of layers from the PSD header before
+/// parsing, since the parser already decodes every layer.
+fn check_limits(psd_data: &[u8], limits: &ProcessingLimits) -> Result<(), JsValue> {
+    let Some(header) = psd_data.get(..PSD_HEADER_LEN) else {
+        return Err(JsValue::from_str("Failed to read PSD: file is too short"));
+    };
+    let height = u32::from_be_bytes([header[14], header[15], header[16], header[17]]);
+    let width = u32::from_be_bytes([header[18], header[19], header[20], header[21]]);
+    limits.check_dimensions(width, height)?;
+
+    // layers can be at most as large as the PSD, the parts outside of it
+    // are cut off
+    let images = layer_count(psd_data).unwrap_or(0) as u64 + IMPORT_IMAGES;
+    limits.check_alloc(width as u64 * height as u64 * 4 * images)?;
+
+    Ok(())
+}
+
+/// The number of layers from the layer info, which follows the color mode
+/// data and the image resources. `None` if the file is cut off before it,
+/// which the parser rejects anyway.
+fn layer_count(psd_data: &[u8]) -> Option<u32> {
+    let section_len = |offset: usize| -> Option<usize> {
+        let bytes = psd_data.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+    };
+
+    let image_resources = PSD_HEADER_LEN
+        .checked_add(4)?
+        .checked_add(section_len(PSD_HEADER_LEN)?)?;
+    let layer_and_mask_info = image_resources
+        .checked_add(4)?
+        .checked_add(section_len(image_resources)?)?;
+    let layer_info = layer_and_mask_info.checked_add(4)?;
+    if section_len(layer_and_mask_info)? == 0 || section_len(layer_info)? == 0 {
+        return Some(0);
+    }
+
+    // a negative count only means that the merged image has transparency
+    let count = psd_data.get(layer_info + 4..layer_info + 6)?;
+    Some(i16::from_be_bytes([count[0], count[1]]).unsigned_abs() as u32)
+}
+
+fn parse_psd(psd_data: &[u8]) -> Result<Psd, JsValue> {
+    Psd::from_bytes(psd_data).map_err(|e| JsValue::from_str(&format!("Failed to read PSD: {e}")))
+}
+
+fn to_image(psd: &Psd, rgba: Vec<u8>) -> Result<DynamicImage, JsValue> {
+    ImageBuffer::<Rgba<u8>, Vec<u8>>::from_vec(psd.width(), psd.height(), rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| JsValue::from_str("Failed to create image from PSD data"))
+}
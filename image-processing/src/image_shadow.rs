@@ -1,5 +1,7 @@
 use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, imageops};
 
+use crate::image_options::{ImageRenderOptions, RenderQuality};
+
 pub trait ImageShadow {
     fn to_shadow(&self, options: &ShadowOptions) -> DynamicImage;
 }
@@ -28,7 +30,13 @@ impl ImageShadow for DynamicImage {
             ])
         });
 
-        imageops::blur(&img, options.blur).into()
+        if options.blur <= 0.0 {
+            img.into()
+        } else if options.fast {
+            imageops::fast_blur(&img, options.blur).into()
+        } else {
+            imageops::blur(&img, options.blur).into()
+        }
     }
 }
 
@@ -44,6 +52,8 @@ pub struct ShadowOptions {
     pub blur: f32,
     pub offset_x: i32,
     pub offset_y: i32,
+    /// Use an approximated blur, which is a lot faster but less accurate.
+    pub fast: bool,
 }
 
 impl ShadowOptions {
@@ -54,10 +64,30 @@ impl ShadowOptions {
             blur,
             offset_x,
             offset_y,
+            fast: false,
         }
     }
 
     pub fn new_black(opacity: f32, blur: f32, offset_x: i32, offset_y: i32) -> Self {
         ShadowOptions::new(Rgb([0, 0, 0]), opacity, blur, offset_x, offset_y)
     }
+
+    /// Adjust the shadow for the render options, scaling blur and offsets
+    /// along with the resolution. Pixel art gets a hard shadow.
+    pub fn for_render(self, options: &ImageRenderOptions) -> Self {
+        let quality = options.quality;
+        let factor = quality.resolution_factor();
+
+        ShadowOptions {
+            blur: if options.pixel_art {
+                0.0
+            } else {
+                self.blur * factor
+            },
+            offset_x: (self.offset_x as f32 * factor).round() as i32,
+            offset_y: (self.offset_y as f32 * factor).round() as i32,
+            fast: quality == RenderQuality::Preview,
+            ..self
+        }
+    }
 }
@@ -0,0 +1,283 @@
+use std::{cell::RefCell, rc::Rc};
+
+use image::{DynamicImage, RgbaImage, imageops::FilterType, metadata::Orientation};
+#[cfg(feature = "svg")]
+use resvg::{tiny_skia, usvg};
+#[cfg(feature = "svg")]
+use wasm_bindgen::JsValue;
+
+#[cfg(feature = "svg")]
+use crate::image_transform::clamp_to_image;
+use crate::{
+    image_options::ImageTransform,
+    image_transform::{Affine, crop_to_source, halve, region_to_canvas, snap_to_pixels, warp},
+};
+
+/// A decoded source image.
+/// The orientation is only applied once the image is drawn, so that
+/// changing it does not touch the full resolution pixels. Cloning is cheap,
+/// the image data is shared.
+#[derive(Clone)]
+pub enum SourceImage {
+    Raster {
+        image: Rc<RasterSource>,
+        orientation: Orientation,
+    },
+    /// SVGs are kept as vector data and only rasterised once the size they
+    /// are drawn at is known, so that they stay crisp at any token size.
+    #[cfg(feature = "svg")]
+    Svg {
+        tree: Rc<usvg::Tree>,
+        orientation: Orientation,
+    },
+}
+
+impl SourceImage {
+    /// Parse an SVG or SVGZ file.
+    /// Text is only drawn if it was converted to paths, since there are no
+    /// fonts available to render it with.
+    #[cfg(feature = "svg")]
+    pub fn from_svg(svg_data: &[u8]) -> Result<SourceImage, JsValue> {
+        let tree = usvg::Tree::from_data(svg_data, &usvg::Options::default())
+            .map_err(|e| JsValue::from_str(&format!("Failed to read SVG: {e}")))?;
+
+        Ok(SourceImage::Svg {
+            tree: Rc::new(tree),
+            orientation: Orientation::NoTransforms,
+        })
+    }
+
+    /// Replace the orientation the image is drawn with.
+    pub fn oriented(self, orientation: Orientation) -> SourceImage {
+        match self {
+            SourceImage::Raster { image, .. } => SourceImage::Raster { image, orientation },
+            #[cfg(feature = "svg")]
+            SourceImage::Svg { tree, .. } => SourceImage::Svg { tree, orientation },
+        }
+    }
+
+    /// The natural size of the image, for SVGs this is the size of the
+    /// viewport. The orientation is not taken into account.
+    pub fn dimensions(&self) -> (u32, u32) {
+        match self {
+            SourceImage::Raster { image, .. } => image.image.dimensions(),
+            #[cfg(feature = "svg")]
+            SourceImage::Svg { tree, .. } => {
+                let size = tree.size();
+                (size.width() as u32, size.height() as u32)
+            }
+        }
+    }
+
+    /// The size of the image with the orientation applied.
+    pub fn oriented_dimensions(&self) -> (u32, u32) {
+        let (width, height) = self.dimensions();
+        let orientation = match self {
+            SourceImage::Raster { orientation, .. } => orientation,
+            #[cfg(feature = "svg")]
+            SourceImage::Svg { orientation, .. } => orientation,
+        };
+
+        oriented_size(width, height, *orientation)
+    }
+
+    /// Draw the image onto a transparent square canvas of the given size.
+    /// Orientation, flip, scale, rotation and position are combined into a
+    /// single transform, so that raster images are only resampled once and
+    /// SVGs are rasterised directly at the size they end up at. Only the
+    /// cropped part of the image is resampled, a crop that reaches past the
+    /// edges of the image adds transparent space. With nearest-neighbour
+    /// sampling, raster images are scaled by whole numbers.
+    pub fn draw(
+        &self,
+        canvas_size: u32,
+        transform: &ImageTransform,
+        filter: FilterType,
+    ) -> RgbaImage {
+        match self {
+            SourceImage::Raster { image, orientation } => {
+                let (width, height) = image.image.dimensions();
+                let region = source_region(transform, width as f32, height as f32, *orientation);
+
+                let snapped;
+                let transform = if filter == FilterType::Nearest {
+                    snapped = snap_to_pixels(region, *orientation, canvas_size, transform);
+                    &snapped
+                } else {
+                    transform
+                };
+                let affine = region_to_canvas(region, *orientation, canvas_size, transform);
+                image.draw(region, &affine, canvas_size, filter)
+            }
+            #[cfg(feature = "svg")]
+            SourceImage::Svg { tree, orientation } => {
+                let size = tree.size();
+                let region = source_region(transform, size.width(), size.height(), *orientation);
+                let Some(clip) = clamp_to_image(region, size.width(), size.height()) else {
+                    return RgbaImage::new(canvas_size, canvas_size);
+                };
+
+                let affine = region_to_canvas(region, *orientation, canvas_size, transform);
+                rasterise_svg(tree, &affine, canvas_size, clip)
+            }
+        }
+    }
+}
+
+/// The part of the stored pixels the transform draws, the crop or else the
+/// whole image.
+fn source_region(
+    transform: &ImageTransform,
+    width: f32,
+    height: f32,
+    orientation: Orientation,
+) -> (f32, f32, f32, f32) {
+    match &transform.crop {
+        Some(crop) => crop_to_source(crop, width, height, orientation),
+        None => (0.0, 0.0, width, height),
+    }
+}
+
+/// The pixels of a raster source image, together with a mip pyramid of
+/// premultiplied copies that are each half the size of the one before. The
+/// levels are only built once the image is drawn smaller than half its size,
+/// and are kept for later draws.
+pub struct RasterSource {
+    image: RgbaImage,
+    levels: RefCell<Vec<Rc<RgbaImage>>>,
+}
+
+impl RasterSource {
+    pub fn new(image: RgbaImage) -> RasterSource {
+        RasterSource {
+            image,
+            levels: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Resample the clipped part of the image onto a canvas with a transform
+    /// of its pixels. Shrinking samples from the smallest pyramid level that
+    /// is still drawn at least at half its size, so the kernel never has to
+    /// cover more than a few pixels of it.
+    fn draw(
+        &self,
+        clip: (f32, f32, f32, f32),
+        affine: &Affine,
+        canvas_size: u32,
+        filter: FilterType,
+    ) -> RgbaImage {
+        let scale = affine.scale_factor();
+        let (mut width, mut height) = self.image.dimensions();
+        let mut level = 0;
+        if filter != FilterType::Nearest {
+            while scale * 2f32.powi(level as i32) < 0.5 && (width > 1 || height > 1) {
+                (width, height) = (width.div_ceil(2), height.div_ceil(2));
+                level += 1;
+            }
+        }
+        if level == 0 {
+            return warp(&self.image, false, clip, affine, canvas_size, filter);
+        }
+
+        let pixels = self.level(level);
+        let factor_x = self.image.width() as f32 / width as f32;
+        let factor_y = self.image.height() as f32 / height as f32;
+        let (left, top, right, bottom) = clip;
+        let clip = (
+            left / factor_x,
+            top / factor_y,
+            right / factor_x,
+            bottom / factor_y,
+        );
+        let affine = Affine::scale(factor_x, factor_y).then(affine);
+        warp(&pixels, true, clip, &affine, canvas_size, filter)
+    }
+
+    /// The pyramid level that is halved the given number of times, building
+    /// the levels up to it if they don't exist yet.
+    fn level(&self, level: usize) -> Rc<RgbaImage> {
+        let mut levels = self.levels.borrow_mut();
+        while levels.len() < level {
+            let next = match levels.last() {
+                Some(previous) => halve(previous, false),
+                None => halve(&self.image, true),
+            };
+            levels.push(Rc::new(next));
+        }
+
+        levels[level - 1].clone()
+    }
+}
+
+impl From<DynamicImage> for SourceImage {
+    fn from(image: DynamicImage) -> Self {
+        SourceImage::Raster {
+            image: Rc::new(RasterSource::new(image.into_rgba8())),
+            orientation: Orientation::NoTransforms,
+        }
+    }
+}
+
+/// The size of an image of the given size with the orientation applied.
+pub fn oriented_size(width: u32, height: u32, orientation: Orientation) -> (u32, u32) {
+    match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    }
+}
+
+/// Whether the data looks like an SVG or a gzip compressed SVGZ file.
+#[cfg(feature = "svg")]
+pub fn is_svg(data: &[u8]) -> bool {
+    if data.starts_with(&[0x1f, 0x8b]) {
+        return true;
+    }
+
+    // the root element has to come within the first few bytes, after the
+    // XML declaration and maybe a comment or doctype
+    let head = String::from_utf8_lossy(&data[..data.len().min(1024)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    (head.starts_with("<?xml") || head.starts_with("<!") || head.starts_with("<svg"))
+        && head.contains("<svg")
+}
+
+#[cfg(feature = "svg")]
+fn rasterise_svg(
+    tree: &usvg::Tree,
+    affine: &Affine,
+    canvas_size: u32,
+    (left, top, right, bottom): (f32, f32, f32, f32),
+) -> RgbaImage {
+    let Some(mut pixmap) = tiny_skia::Pixmap::new(canvas_size, canvas_size) else {
+        return RgbaImage::new(canvas_size, canvas_size);
+    };
+    let transform =
+        tiny_skia::Transform::from_row(affine.a, affine.d, affine.b, affine.e, affine.c, affine.f);
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+
+    // drop everything outside of the crop and the viewbox
+    let Some(mut mask) = tiny_skia::Mask::new(canvas_size, canvas_size) else {
+        return RgbaImage::new(canvas_size, canvas_size);
+    };
+    if let Some(rect) = tiny_skia::Rect::from_ltrb(left, top, right, bottom) {
+        let path = tiny_skia::PathBuilder::from_rect(rect);
+        mask.fill_path(&path, tiny_skia::FillRule::Winding, true, transform);
+    }
+    pixmap.apply_mask(&mask);
+
+    // tiny-skia works with premultiplied alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let pixel = pixel.demultiply();
+            [pixel.red(), pixel.green(), pixel.blue(), pixel.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(canvas_size, canvas_size, pixels)
+        .unwrap_or_else(|| RgbaImage::new(canvas_size, canvas_size))
+}
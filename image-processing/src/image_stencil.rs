@@ -7,6 +7,11 @@ pub trait ImageStencil {
     fn to_stencil<'a>(&'a self, threshold: u8) -> StencilMask<'a>;
     fn to_inverted_stencil<'a>(&'a self, threshold: u8) -> StencilMask<'a>;
 
+    /// A stencil that uses the alpha of the mask as coverage, so that
+    /// partially covered pixels are partially kept.
+    fn to_soft_stencil<'a>(&'a self) -> StencilMask<'a>;
+    fn to_inverted_soft_stencil<'a>(&'a self) -> StencilMask<'a>;
+
     fn stencil(&self, mask: &StencilMask) -> DynamicImage;
 
     fn stencil_and(&self, masks: &[&StencilMask]) -> DynamicImage;
@@ -23,54 +28,62 @@ impl ImageStencil for DynamicImage {
         StencilMask::new(self, true, threshold)
     }
 
+    fn to_soft_stencil<'a>(&'a self) -> StencilMask<'a> {
+        StencilMask::soft(self, false)
+    }
+
+    fn to_inverted_soft_stencil<'a>(&'a self) -> StencilMask<'a> {
+        StencilMask::soft(self, true)
+    }
+
     fn stencil(&self, mask: &StencilMask) -> DynamicImage {
         ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
-            let mask_pixel = mask.image.get_pixel(x, y);
-            if (mask_pixel[3] > mask.threshold) ^ mask.invert {
-                self.get_pixel(x, y)
-            } else {
-                Rgba([0, 0, 0, 0]) // Transparent pixel
-            }
+            with_coverage(self.get_pixel(x, y), mask.coverage(x, y))
         })
         .into()
     }
 
     fn stencil_and(&self, masks: &[&StencilMask]) -> DynamicImage {
         ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
-            let show_pixel = masks
-                .iter()
-                .all(|mask| (mask.image.get_pixel(x, y)[3] > mask.threshold) ^ mask.invert);
+            let coverage = masks.iter().map(|mask| mask.coverage(x, y)).product();
 
-            if show_pixel {
-                self.get_pixel(x, y)
-            } else {
-                Rgba([0, 0, 0, 0]) // Transparent pixel
-            }
+            with_coverage(self.get_pixel(x, y), coverage)
         })
         .into()
     }
 
     fn stencil_or(&self, masks: &[&StencilMask]) -> DynamicImage {
         ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
-            let show_pixel = masks
+            let coverage = masks
                 .iter()
-                .any(|mask| (mask.image.get_pixel(x, y)[3] > mask.threshold) ^ mask.invert);
+                .map(|mask| mask.coverage(x, y))
+                .fold(0.0, f32::max);
 
-            if show_pixel {
-                self.get_pixel(x, y)
-            } else {
-                Rgba([0, 0, 0, 0]) // Transparent pixel
-            }
+            with_coverage(self.get_pixel(x, y), coverage)
         })
         .into()
     }
 }
 
+/// Scale the alpha of the pixel by the coverage.
+fn with_coverage(pixel: Rgba<u8>, coverage: f32) -> Rgba<u8> {
+    if coverage >= 1.0 {
+        pixel
+    } else if coverage <= 0.0 {
+        Rgba([0, 0, 0, 0]) // Transparent pixel
+    } else {
+        let alpha = (pixel[3] as f32 * coverage).round() as u8;
+        Rgba([pixel[0], pixel[1], pixel[2], alpha])
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct StencilMask<'a> {
     pub image: &'a DynamicImage,
     pub invert: bool,
     pub threshold: u8,
+    /// Use the alpha as coverage instead of comparing it to the threshold.
+    pub soft: bool,
 }
 
 impl<'a> StencilMask<'a> {
@@ -79,10 +92,66 @@ impl<'a> StencilMask<'a> {
             image,
             invert,
             threshold,
+            soft: false,
+        }
+    }
+
+    pub fn soft(image: &'a DynamicImage, invert: bool) -> Self {
+        StencilMask {
+            image,
+            invert,
+            threshold: 0,
+            soft: true,
+        }
+    }
+
+    /// How much of the pixel is kept, between 0 and 1.
+    pub fn coverage(&self, x: u32, y: u32) -> f32 {
+        let alpha = self.image.get_pixel(x, y)[3];
+        if self.soft {
+            let coverage = alpha as f32 / 255.0;
+            if self.invert { 1.0 - coverage } else { coverage }
+        } else if (alpha > self.threshold) ^ self.invert {
+            1.0
+        } else {
+            0.0
         }
     }
 }
 
+/// Blend from one image to the other by the coverage of the mask. The
+/// colours are blended premultiplied by their alpha, so that transparent
+/// pixels don't pull their colour into the blend.
+pub fn lerp_images(from: &DynamicImage, to: &DynamicImage, mask: &StencilMask) -> DynamicImage {
+    ImageBuffer::from_fn(from.width(), from.height(), |x, y| {
+        let coverage = mask.coverage(x, y);
+        if coverage <= 0.0 {
+            return from.get_pixel(x, y);
+        }
+        if coverage >= 1.0 {
+            return to.get_pixel(x, y);
+        }
+
+        let (from, to) = (from.get_pixel(x, y), to.get_pixel(x, y));
+        let weight = |pixel: Rgba<u8>, weight: f32| pixel[3] as f32 / 255.0 * weight;
+        let (from_weight, to_weight) = (weight(from, 1.0 - coverage), weight(to, coverage));
+        let alpha = from_weight + to_weight;
+        if alpha <= 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+
+        let channel =
+            |i: usize| (from[i] as f32 * from_weight + to[i] as f32 * to_weight) / alpha;
+        Rgba([
+            channel(0).round() as u8,
+            channel(1).round() as u8,
+            channel(2).round() as u8,
+            (alpha * 255.0).round() as u8,
+        ])
+    })
+    .into()
+}
+
 pub fn overlay_images(dimensions: &ImageDimensions, images: &[&DynamicImage]) -> DynamicImage {
     ImageBuffer::from_fn(dimensions.size, dimensions.size, |x, y| {
         let mut final_pixel = (0.0, 0.0, 0.0, 0.0); // (r, g, b, a)
@@ -105,3 +174,65 @@ pub fn overlay_images(dimensions: &ImageDimensions, images: &[&DynamicImage]) ->
         ])
     }).into()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pixel_with_alpha(alpha: u8) -> DynamicImage {
+        DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([255, 255, 255, alpha])))
+    }
+
+    #[test]
+    fn soft_stencils_use_the_alpha_as_coverage() {
+        let mask = pixel_with_alpha(51);
+
+        assert_eq!(mask.to_soft_stencil().coverage(0, 0), 0.2);
+        assert_eq!(mask.to_inverted_soft_stencil().coverage(0, 0), 0.8);
+    }
+
+    #[test]
+    fn hard_stencils_compare_with_the_threshold() {
+        let mask = pixel_with_alpha(51);
+
+        assert_eq!(mask.to_stencil(50).coverage(0, 0), 1.0);
+        assert_eq!(mask.to_stencil(51).coverage(0, 0), 0.0);
+        assert_eq!(mask.to_inverted_stencil(51).coverage(0, 0), 1.0);
+    }
+
+    #[test]
+    fn stencils_combine_coverage() {
+        let image =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([10, 20, 30, 200])));
+        let (half, quarter) = (pixel_with_alpha(128), pixel_with_alpha(64));
+        let masks = [&half.to_soft_stencil(), &quarter.to_soft_stencil()];
+
+        assert_eq!(image.stencil(masks[0]).get_pixel(0, 0).0, [10, 20, 30, 100]);
+        // both have to cover the pixel
+        assert_eq!(
+            image.stencil_and(&masks).get_pixel(0, 0).0,
+            [10, 20, 30, 25]
+        );
+        // either one covers the pixel
+        assert_eq!(
+            image.stencil_or(&masks).get_pixel(0, 0).0,
+            [10, 20, 30, 100]
+        );
+    }
+
+    #[test]
+    fn lerp_blends_premultiplied() {
+        let red = DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([255, 0, 0, 255])));
+        let transparent =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(1, 1, Rgba([0, 0, 255, 0])));
+        let half = pixel_with_alpha(128);
+
+        // the colour of the transparent pixel does not bleed into the blend
+        let blended = lerp_images(&transparent, &red, &half.to_soft_stencil());
+        assert_eq!(blended.get_pixel(0, 0).0, [255, 0, 0, 128]);
+
+        let none = pixel_with_alpha(0);
+        let blended = lerp_images(&red, &transparent, &none.to_soft_stencil());
+        assert_eq!(blended.get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+}
@@ -0,0 +1,914 @@
+use std::ops::Range;
+
+use image::{Rgba, RgbaImage, imageops::FilterType, metadata::Orientation};
+
+use crate::image_options::{ImageTransform, SourceCrop};
+
+/// A 2D affine transform, mapping `(x, y)` to
+/// `(a * x + b * y + c, d * x + e * y + f)`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Affine {
+    pub a: f32,
+    pub b: f32,
+    pub c: f32,
+    pub d: f32,
+    pub e: f32,
+    pub f: f32,
+}
+
+impl Affine {
+    pub const fn new(a: f32, b: f32, c: f32, d: f32, e: f32, f: f32) -> Affine {
+        Affine { a, b, c, d, e, f }
+    }
+
+    pub fn translate(x: f32, y: f32) -> Affine {
+        Affine::new(1.0, 0.0, x, 0.0, 1.0, y)
+    }
+
+    pub fn scale(x: f32, y: f32) -> Affine {
+        Affine::new(x, 0.0, 0.0, 0.0, y, 0.0)
+    }
+
+    /// Clockwise rotation in degrees, since the y axis points down.
+    pub fn rotate(degrees: f32) -> Affine {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        Affine::new(cos, -sin, 0.0, sin, cos, 0.0)
+    }
+
+    /// The EXIF orientation around the origin.
+    pub fn orientation(orientation: Orientation) -> Affine {
+        let (a, b, d, e) = match orientation {
+            Orientation::NoTransforms => (1.0, 0.0, 0.0, 1.0),
+            Orientation::Rotate90 => (0.0, -1.0, 1.0, 0.0),
+            Orientation::Rotate180 => (-1.0, 0.0, 0.0, -1.0),
+            Orientation::Rotate270 => (0.0, 1.0, -1.0, 0.0),
+            Orientation::FlipHorizontal => (-1.0, 0.0, 0.0, 1.0),
+            Orientation::FlipVertical => (1.0, 0.0, 0.0, -1.0),
+            Orientation::Rotate90FlipH => (0.0, 1.0, 1.0, 0.0),
+            Orientation::Rotate270FlipH => (0.0, -1.0, -1.0, 0.0),
+        };
+        Affine::new(a, b, 0.0, d, e, 0.0)
+    }
+
+    /// Apply `self` first and `next` second.
+    pub fn then(&self, next: &Affine) -> Affine {
+        Affine::new(
+            next.a * self.a + next.b * self.d,
+            next.a * self.b + next.b * self.e,
+            next.a * self.c + next.b * self.f + next.c,
+            next.d * self.a + next.e * self.d,
+            next.d * self.b + next.e * self.e,
+            next.d * self.c + next.e * self.f + next.f,
+        )
+    }
+
+    pub fn apply(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            self.a * x + self.b * y + self.c,
+            self.d * x + self.e * y + self.f,
+        )
+    }
+
+    pub fn invert(&self) -> Option<Affine> {
+        let det = self.a * self.e - self.b * self.d;
+        if det.abs() < f32::EPSILON {
+            return None;
+        }
+
+        Some(Affine::new(
+            self.e / det,
+            -self.b / det,
+            (self.b * self.f - self.e * self.c) / det,
+            -self.d / det,
+            self.a / det,
+            (self.d * self.c - self.a * self.f) / det,
+        ))
+    }
+
+    /// How much lengths are scaled, assuming the scale is uniform.
+    pub fn scale_factor(&self) -> f32 {
+        (self.a * self.e - self.b * self.d).abs().sqrt()
+    }
+}
+
+/// The mapping from the pixels of a source image of the given size to the
+/// canvas. In order: orientation, flip, scale and rotation around the
+/// image centre, then moving the centre to the canvas centre plus the
+/// position of the transform.
+pub fn source_to_canvas(
+    width: f32,
+    height: f32,
+    orientation: Orientation,
+    canvas_size: u32,
+    transform: &ImageTransform,
+) -> Affine {
+    let flip = if transform.flipped { -1.0 } else { 1.0 };
+    let center = canvas_size as f32 / 2.0;
+
+    Affine::translate(-width / 2.0, -height / 2.0)
+        .then(&Affine::orientation(orientation))
+        .then(&Affine::scale(flip * transform.scale, transform.scale))
+        .then(&Affine::rotate(transform.rotation))
+        .then(&Affine::translate(
+            center + transform.pos_x,
+            center + transform.pos_y,
+        ))
+}
+
+/// The mapping from the pixels of the given region of a source image to the
+/// canvas, where the centre of the region takes the place of the image
+/// centre. See [`source_to_canvas`].
+pub fn region_to_canvas(
+    (left, top, right, bottom): (f32, f32, f32, f32),
+    orientation: Orientation,
+    canvas_size: u32,
+    transform: &ImageTransform,
+) -> Affine {
+    Affine::translate(-left, -top).then(&source_to_canvas(
+        right - left,
+        bottom - top,
+        orientation,
+        canvas_size,
+        transform,
+    ))
+}
+
+/// The crop rectangle in the coordinates of the stored pixels as left, top,
+/// right and bottom. The crop is given in the upright image, so the
+/// orientation has to be undone first. It is not clamped to the image, since
+/// a crop that reaches past the edges adds transparent space and still
+/// positions the image by its centre.
+pub fn crop_to_source(
+    crop: &SourceCrop,
+    width: f32,
+    height: f32,
+    orientation: Orientation,
+) -> (f32, f32, f32, f32) {
+    let rotation = Affine::orientation(orientation);
+    let (upright_width, upright_height) = if rotation.b != 0.0 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    // orientations only rotate and mirror, so the transpose undoes them
+    let undo_rotation = Affine::new(rotation.a, rotation.d, 0.0, rotation.b, rotation.e, 0.0);
+    let upright_to_source = Affine::translate(-upright_width / 2.0, -upright_height / 2.0)
+        .then(&undo_rotation)
+        .then(&Affine::translate(width / 2.0, height / 2.0));
+
+    let corners = [
+        (crop.x, crop.y),
+        (crop.x + crop.width, crop.y + crop.height),
+    ]
+    .map(|(x, y)| upright_to_source.apply(x, y));
+
+    (
+        corners[0].0.min(corners[1].0),
+        corners[0].1.min(corners[1].1),
+        corners[0].0.max(corners[1].0),
+        corners[0].1.max(corners[1].1),
+    )
+}
+
+/// The part of the region that lies within an image of the given size, or
+/// `None` if nothing of the image is left.
+pub fn clamp_to_image(
+    (left, top, right, bottom): (f32, f32, f32, f32),
+    width: f32,
+    height: f32,
+) -> Option<(f32, f32, f32, f32)> {
+    let (left, top) = (left.max(0.0), top.max(0.0));
+    let (right, bottom) = (right.min(width), bottom.min(height));
+
+    (left < right && top < bottom).then_some((left, top, right, bottom))
+}
+
+/// Nearest-neighbour sampling only keeps all source pixels the same size and
+/// square if the scale is a whole number, or one over a whole number, the
+/// rotation is a multiple of 90 degrees, and the source pixels line up with
+/// the canvas pixels. Round the transform to the closest one that does.
+pub fn snap_to_pixels(
+    region: (f32, f32, f32, f32),
+    orientation: Orientation,
+    canvas_size: u32,
+    transform: &ImageTransform,
+) -> ImageTransform {
+    let scale = if transform.scale >= 1.0 {
+        transform.scale.round()
+    } else if transform.scale > 0.0 {
+        1.0 / (1.0 / transform.scale).round()
+    } else {
+        transform.scale
+    };
+    let snapped = ImageTransform {
+        scale,
+        rotation: (transform.rotation / 90.0).round() * 90.0,
+        ..transform.clone()
+    };
+
+    // move the image so that the corner of the source lands on a pixel
+    let affine = region_to_canvas(region, orientation, canvas_size, &snapped);
+    ImageTransform {
+        pos_x: snapped.pos_x + affine.c.round() - affine.c,
+        pos_y: snapped.pos_y + affine.f.round() - affine.f,
+        ..snapped
+    }
+}
+
+/// Resample the source onto a transparent canvas of the given size.
+/// Transforms that keep the axes aligned, like scaling, flipping and
+/// rotating by multiples of 90 degrees, are resampled in two separable
+/// passes, everything else in a single pass with a 2D kernel. Only the part
+/// of the canvas the source lands on is touched. `premultiplied` tells
+/// whether the colour of the source is already premultiplied by its alpha.
+/// Only the source pixels within the clip rectangle are sampled, pixels it
+/// covers partly are faded by the covered fraction.
+pub fn warp(
+    source: &RgbaImage,
+    premultiplied: bool,
+    clip: (f32, f32, f32, f32),
+    affine: &Affine,
+    canvas_size: u32,
+    filter: FilterType,
+) -> RgbaImage {
+    let mut canvas = RgbaImage::new(canvas_size, canvas_size);
+    let Some(clip) = clamp_to_image(clip, source.width() as f32, source.height() as f32) else {
+        return canvas;
+    };
+    let Some(inverse) = affine.invert() else {
+        return canvas;
+    };
+
+    let (_, support) = kernel(filter);
+    // the kernel is widened when shrinking, so that every source pixel counts
+    let margin = support * affine.scale_factor().max(1.0);
+    let Some(bounds) = canvas_bounds(clip, affine, margin, canvas_size) else {
+        return canvas;
+    };
+    let source = Samples::new(source, premultiplied, clip);
+
+    match axis_mapping(&inverse, canvas_size) {
+        Some(mapping) if filter != FilterType::Nearest => {
+            warp_separable(&source, &inverse, mapping, bounds, filter, &mut canvas)
+        }
+        _ => warp_2d(&source, &inverse, bounds, filter, &mut canvas),
+    }
+
+    canvas
+}
+
+/// How the axes of the source follow the axes of the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AxisMapping {
+    /// The source x changes along the canvas x, the source y along the
+    /// canvas y.
+    Straight,
+    /// The source x changes along the canvas y and the other way round.
+    Swapped,
+}
+
+/// The axis mapping of a transform from the canvas to the source, or `None`
+/// if it rotates by anything other than multiples of 90 degrees.
+fn axis_mapping(inverse: &Affine, canvas_size: u32) -> Option<AxisMapping> {
+    // the sine and cosine of multiples of 90 degrees are not exactly 0, but
+    // they can be ignored as long as they add up to less than a thousandth
+    // of a pixel across the canvas
+    let negligible = |value: f32| value.abs() * canvas_size as f32 <= 1e-3;
+
+    if negligible(inverse.b) && negligible(inverse.d) {
+        Some(AxisMapping::Straight)
+    } else if negligible(inverse.a) && negligible(inverse.e) {
+        Some(AxisMapping::Swapped)
+    } else {
+        None
+    }
+}
+
+/// Resample an axis aligned transform along the source x first, then along
+/// the source y.
+fn warp_separable(
+    source: &Samples,
+    inverse: &Affine,
+    mapping: AxisMapping,
+    (min_x, min_y, max_x, max_y): (u32, u32, u32, u32),
+    filter: FilterType,
+    canvas: &mut RgbaImage,
+) {
+    // the canvas pixels along which the source x and y change
+    let (columns, column_scale, rows, row_scale) = match mapping {
+        AxisMapping::Straight => (min_x..max_x, inverse.a, min_y..max_y, inverse.e),
+        AxisMapping::Swapped => (min_y..max_y, inverse.b, min_x..max_x, inverse.d),
+    };
+    let taps_x = axis_taps(columns.clone(), column_scale, inverse.c, filter);
+    let taps_y = axis_taps(rows.clone(), row_scale, inverse.f, filter);
+
+    let height = source.image.height() as i64;
+    // only the source rows that any of the taps reach are resampled
+    let first_row = taps_y
+        .iter()
+        .map(|(start, _)| *start)
+        .min()
+        .unwrap_or(0)
+        .clamp(0, height);
+    let last_row = taps_y
+        .iter()
+        .map(|(start, weights)| start + weights.len() as i64)
+        .max()
+        .unwrap_or(0)
+        .clamp(first_row, height);
+
+    let columns_len = taps_x.len();
+    let mut resampled_rows = vec![[0.0f32; 4]; (last_row - first_row) as usize * columns_len];
+    for (sy, row) in (first_row..last_row).zip(resampled_rows.chunks_exact_mut(columns_len)) {
+        for (sum, (start, weights)) in row.iter_mut().zip(&taps_x) {
+            for (sx, weight) in (*start..).zip(weights) {
+                let Some(pixel) = source.get(sx, sy) else {
+                    continue;
+                };
+                for (sum, value) in sum.iter_mut().zip(pixel) {
+                    *sum += value * weight;
+                }
+            }
+        }
+    }
+
+    for (row, (start, weights)) in rows.zip(&taps_y) {
+        for (column_index, column) in columns.clone().enumerate() {
+            let mut sum = [0.0f32; 4];
+            for (sy, weight) in (*start..).zip(weights) {
+                if !(first_row..last_row).contains(&sy) {
+                    continue;
+                }
+                let resampled =
+                    resampled_rows[(sy - first_row) as usize * columns_len + column_index];
+                for (sum, value) in sum.iter_mut().zip(resampled) {
+                    *sum += value * weight;
+                }
+            }
+
+            let Some(pixel) = unpremultiply(sum) else {
+                continue;
+            };
+            match mapping {
+                AxisMapping::Straight => canvas.put_pixel(column, row, pixel),
+                AxisMapping::Swapped => canvas.put_pixel(row, column, pixel),
+            }
+        }
+    }
+}
+
+/// The source pixels a kernel covers for each canvas pixel along one axis,
+/// where canvas pixel `i` maps to the source coordinate
+/// `scale * (i + 0.5) + offset`, as the first source pixel and the weights.
+/// The weights sum up to 1 including the taps outside of the source, so
+/// those count as transparent.
+fn axis_taps(
+    canvas: Range<u32>,
+    scale: f32,
+    offset: f32,
+    filter: FilterType,
+) -> Vec<(i64, Vec<f32>)> {
+    let (kernel, support) = kernel(filter);
+    // widen the kernel when shrinking, so that every source pixel counts
+    let kernel_scale = scale.abs().max(1.0);
+    let radius = support * kernel_scale;
+
+    canvas
+        .map(|i| {
+            let center = scale * (i as f32 + 0.5) + offset;
+            let start = (center - radius - 0.5).ceil() as i64;
+            let mut weights = Vec::new();
+            let mut source = start;
+            while (source as f32 + 0.5 - center).abs() <= radius {
+                weights.push(kernel((source as f32 + 0.5 - center) / kernel_scale));
+                source += 1;
+            }
+
+            let sum: f32 = weights.iter().sum();
+            if sum != 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= sum);
+            }
+            (start, weights)
+        })
+        .collect()
+}
+
+/// Resample any transform with a 2D kernel for every canvas pixel.
+fn warp_2d(
+    source: &Samples,
+    inverse: &Affine,
+    (min_x, min_y, max_x, max_y): (u32, u32, u32, u32),
+    filter: FilterType,
+    canvas: &mut RgbaImage,
+) {
+    let (kernel, support) = kernel(filter);
+    // widen the kernel when shrinking, so that every source pixel counts
+    let kernel_scale = inverse.scale_factor().max(1.0);
+    let radius = support * kernel_scale;
+
+    let mut weights_x = Vec::new();
+    let mut weights_y = Vec::new();
+
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let (u, v) = inverse.apply(x as f32 + 0.5, y as f32 + 0.5);
+
+            if filter == FilterType::Nearest {
+                let pixel = source.get(u.floor() as i64, v.floor() as i64);
+                if let Some(pixel) = pixel.and_then(unpremultiply) {
+                    canvas.put_pixel(x, y, pixel);
+                }
+                continue;
+            }
+
+            let start_x = (u - radius - 0.5).ceil() as i64;
+            let start_y = (v - radius - 0.5).ceil() as i64;
+            weights_x.clear();
+            weights_y.clear();
+            let mut sx = start_x;
+            while (sx as f32 + 0.5 - u).abs() <= radius {
+                weights_x.push(kernel((sx as f32 + 0.5 - u) / kernel_scale));
+                sx += 1;
+            }
+            let mut sy = start_y;
+            while (sy as f32 + 0.5 - v).abs() <= radius {
+                weights_y.push(kernel((sy as f32 + 0.5 - v) / kernel_scale));
+                sy += 1;
+            }
+
+            // pixels outside of the source or the clip count as transparent,
+            // which smooths the edges
+            let mut sum = [0.0f32; 4];
+            let mut weight_sum = 0.0;
+            for (sy, weight_y) in (start_y..).zip(&weights_y) {
+                for (sx, weight_x) in (start_x..).zip(&weights_x) {
+                    let weight = weight_x * weight_y;
+                    weight_sum += weight;
+
+                    let Some(pixel) = source.get(sx, sy) else {
+                        continue;
+                    };
+                    for (sum, value) in sum.iter_mut().zip(pixel) {
+                        *sum += value * weight;
+                    }
+                }
+            }
+
+            if weight_sum <= 0.0 {
+                continue;
+            }
+            if let Some(pixel) = unpremultiply(sum.map(|value| value / weight_sum)) {
+                canvas.put_pixel(x, y, pixel);
+            }
+        }
+    }
+}
+
+/// The pixels a warp samples from, with how much of each column and row the
+/// clip rectangle covers.
+struct Samples<'a> {
+    image: &'a RgbaImage,
+    premultiplied: bool,
+    columns: Vec<f32>,
+    rows: Vec<f32>,
+}
+
+impl Samples<'_> {
+    fn new(
+        image: &RgbaImage,
+        premultiplied: bool,
+        (left, top, right, bottom): (f32, f32, f32, f32),
+    ) -> Samples<'_> {
+        let coverage = |len: u32, start: f32, end: f32| {
+            (0..len)
+                .map(|i| ((i + 1) as f32).min(end) - (i as f32).max(start))
+                .map(|covered| covered.clamp(0.0, 1.0))
+                .collect()
+        };
+
+        Samples {
+            image,
+            premultiplied,
+            columns: coverage(image.width(), left, right),
+            rows: coverage(image.height(), top, bottom),
+        }
+    }
+
+    /// The premultiplied channels of the pixel, faded by how much of it the
+    /// clip rectangle covers, or `None` if it lies outside of it.
+    fn get(&self, x: i64, y: i64) -> Option<[f32; 4]> {
+        let column = *self.columns.get(usize::try_from(x).ok()?)?;
+        let row = *self.rows.get(usize::try_from(y).ok()?)?;
+        let coverage = column * row;
+        if coverage <= 0.0 {
+            return None;
+        }
+
+        let pixel =
+            premultiplied_pixel(self.image.get_pixel(x as u32, y as u32), self.premultiplied);
+        Some(pixel.map(|value| value * coverage))
+    }
+}
+
+/// The channels of the pixel with the colour premultiplied by the alpha.
+/// Averaging straight alpha pulls the colour of transparent pixels into the
+/// edges, which shows up as dark fringes.
+fn premultiplied_pixel(pixel: &Rgba<u8>, premultiplied: bool) -> [f32; 4] {
+    let [red, green, blue, alpha] = pixel.0.map(|value| value as f32);
+    if premultiplied {
+        return [red, green, blue, alpha];
+    }
+
+    let factor = alpha / 255.0;
+    [red * factor, green * factor, blue * factor, alpha]
+}
+
+/// Turn resampled premultiplied channels back into a straight pixel, or
+/// `None` if it is transparent.
+fn unpremultiply(channels: [f32; 4]) -> Option<Rgba<u8>> {
+    let alpha = channels[3].clamp(0.0, 255.0);
+    if alpha < 0.5 {
+        return None;
+    }
+
+    // negative kernel lobes can push the colour above the alpha
+    let color = |value: f32| (value / alpha * 255.0).clamp(0.0, 255.0) as u8;
+    Some(Rgba([
+        color(channels[0]),
+        color(channels[1]),
+        color(channels[2]),
+        alpha.round() as u8,
+    ]))
+}
+
+/// Halve the size of the image by averaging blocks of 2x2 pixels, for the
+/// levels of a mip pyramid. The levels are premultiplied, so a straight
+/// source is premultiplied while it is halved. The last row and column of
+/// odd sizes average the pixels that exist.
+pub fn halve(image: &RgbaImage, premultiply: bool) -> RgbaImage {
+    let (width, height) = (image.width(), image.height());
+
+    RgbaImage::from_fn(width.div_ceil(2), height.div_ceil(2), |x, y| {
+        let mut sum = [0.0f32; 4];
+        let mut count = 0.0;
+        for sy in 2 * y..(2 * y + 2).min(height) {
+            for sx in 2 * x..(2 * x + 2).min(width) {
+                let pixel = premultiplied_pixel(image.get_pixel(sx, sy), !premultiply);
+                for (sum, value) in sum.iter_mut().zip(pixel) {
+                    *sum += value;
+                }
+                count += 1.0;
+            }
+        }
+
+        Rgba(sum.map(|value| (value / count).round() as u8))
+    })
+}
+
+/// The area of the canvas the clipped source covers, padded by the given
+/// margin.
+fn canvas_bounds(
+    (left, top, right, bottom): (f32, f32, f32, f32),
+    affine: &Affine,
+    margin: f32,
+    canvas_size: u32,
+) -> Option<(u32, u32, u32, u32)> {
+    let corners = [(left, top), (right, top), (left, bottom), (right, bottom)]
+        .map(|(x, y)| affine.apply(x, y));
+
+    let min_x = corners.iter().map(|c| c.0).fold(f32::INFINITY, f32::min) - margin;
+    let min_y = corners.iter().map(|c| c.1).fold(f32::INFINITY, f32::min) - margin;
+    let max_x = corners
+        .iter()
+        .map(|c| c.0)
+        .fold(f32::NEG_INFINITY, f32::max)
+        + margin;
+    let max_y = corners
+        .iter()
+        .map(|c| c.1)
+        .fold(f32::NEG_INFINITY, f32::max)
+        + margin;
+
+    let clamp = |value: f32| value.clamp(0.0, canvas_size as f32) as u32;
+    let bounds = (
+        clamp(min_x.floor()),
+        clamp(min_y.floor()),
+        clamp(max_x.ceil()),
+        clamp(max_y.ceil()),
+    );
+
+    (bounds.0 < bounds.2 && bounds.1 < bounds.3).then_some(bounds)
+}
+
+/// The resampling kernel of the filter and its support radius, matching the
+/// kernels of the image crate.
+fn kernel(filter: FilterType) -> (fn(f32) -> f32, f32) {
+    match filter {
+        FilterType::Nearest => (|_| 1.0, 0.5),
+        FilterType::Triangle => (|x| (1.0 - x.abs()).max(0.0), 1.0),
+        FilterType::CatmullRom => (catmull_rom, 2.0),
+        FilterType::Gaussian => (|x| (-2.0 * x * x).exp(), 3.0),
+        FilterType::Lanczos3 => (lanczos3, 3.0),
+    }
+}
+
+fn catmull_rom(x: f32) -> f32 {
+    let x = x.abs();
+    if x < 1.0 {
+        1.5 * x * x * x - 2.5 * x * x + 1.0
+    } else if x < 2.0 {
+        -0.5 * x * x * x + 2.5 * x * x - 4.0 * x + 2.0
+    } else {
+        0.0
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() >= 3.0 {
+        return 0.0;
+    }
+    sinc(x) * sinc(x / 3.0)
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORIENTATIONS: [Orientation; 8] = [
+        Orientation::NoTransforms,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::FlipHorizontal,
+        Orientation::FlipVertical,
+        Orientation::Rotate90FlipH,
+        Orientation::Rotate270FlipH,
+    ];
+
+    fn transform(pos_x: f32, pos_y: f32, scale: f32, flipped: bool) -> ImageTransform {
+        ImageTransform {
+            pos_x,
+            pos_y,
+            scale,
+            flipped,
+            rotation: 0.0,
+            crop: None,
+        }
+    }
+
+    fn upright_size(width: f32, height: f32, orientation: Orientation) -> (f32, f32) {
+        if Affine::orientation(orientation).b != 0.0 {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    /// The bounding box of the rectangle after the transform.
+    fn bounds(affine: &Affine, (left, top, right, bottom): (f32, f32, f32, f32)) -> [f32; 4] {
+        let corners = [(left, top), (right, top), (left, bottom), (right, bottom)]
+            .map(|(x, y)| affine.apply(x, y));
+        let xs = corners.map(|corner| corner.0);
+        let ys = corners.map(|corner| corner.1);
+
+        [
+            xs.into_iter().fold(f32::INFINITY, f32::min),
+            ys.into_iter().fold(f32::INFINITY, f32::min),
+            xs.into_iter().fold(f32::NEG_INFINITY, f32::max),
+            ys.into_iter().fold(f32::NEG_INFINITY, f32::max),
+        ]
+    }
+
+    fn assert_close<const N: usize>(a: [f32; N], b: [f32; N]) {
+        assert!(
+            a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-3),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn crop_to_source_undoes_the_orientation() {
+        let crop = SourceCrop {
+            x: 3.0,
+            y: 5.0,
+            width: 10.0,
+            height: 7.0,
+        };
+
+        for orientation in ORIENTATIONS {
+            let (width, height) = (40.0, 20.0);
+            let (upright_width, upright_height) = upright_size(width, height, orientation);
+            let source_to_upright = Affine::translate(-width / 2.0, -height / 2.0)
+                .then(&Affine::orientation(orientation))
+                .then(&Affine::translate(
+                    upright_width / 2.0,
+                    upright_height / 2.0,
+                ));
+
+            let region = crop_to_source(&crop, width, height, orientation);
+            assert_close(bounds(&source_to_upright, region), [3.0, 5.0, 13.0, 12.0]);
+        }
+    }
+
+    #[test]
+    fn crop_to_source_of_a_rotated_image() {
+        // the upright top right corner is the stored top left corner
+        let crop = SourceCrop {
+            x: 10.0,
+            y: 0.0,
+            width: 10.0,
+            height: 10.0,
+        };
+        let region = crop_to_source(&crop, 40.0, 20.0, Orientation::Rotate90);
+
+        assert_close(region.into(), [0.0, 0.0, 10.0, 10.0]);
+    }
+
+    #[test]
+    fn crop_past_the_edges_is_not_clamped() {
+        let crop = SourceCrop {
+            x: -10.0,
+            y: 0.0,
+            width: 60.0,
+            height: 20.0,
+        };
+        let region = crop_to_source(&crop, 40.0, 20.0, Orientation::NoTransforms);
+
+        assert_close(region.into(), [-10.0, 0.0, 50.0, 20.0]);
+        assert_eq!(
+            clamp_to_image(region, 40.0, 20.0),
+            Some((0.0, 0.0, 40.0, 20.0))
+        );
+        assert_eq!(clamp_to_image((50.0, 0.0, 60.0, 20.0), 40.0, 20.0), None);
+    }
+
+    #[test]
+    fn source_to_canvas_centres_the_upright_image() {
+        for orientation in ORIENTATIONS {
+            let affine = source_to_canvas(
+                40.0,
+                20.0,
+                orientation,
+                100,
+                &transform(3.0, 4.0, 2.0, false),
+            );
+            let (upright_width, upright_height) = upright_size(40.0, 20.0, orientation);
+
+            assert_close(
+                bounds(&affine, (0.0, 0.0, 40.0, 20.0)),
+                [
+                    53.0 - upright_width,
+                    54.0 - upright_height,
+                    53.0 + upright_width,
+                    54.0 + upright_height,
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn source_to_canvas_flips_horizontally() {
+        let affine = source_to_canvas(
+            40.0,
+            20.0,
+            Orientation::NoTransforms,
+            100,
+            &transform(3.0, 4.0, 2.0, true),
+        );
+
+        assert_close(affine.apply(0.0, 0.0).into(), [93.0, 34.0]);
+    }
+
+    #[test]
+    fn region_to_canvas_centres_the_region() {
+        let affine = region_to_canvas(
+            (10.0, 5.0, 30.0, 15.0),
+            Orientation::NoTransforms,
+            100,
+            &transform(0.0, 0.0, 1.0, false),
+        );
+
+        assert_close(affine.apply(20.0, 10.0).into(), [50.0, 50.0]);
+    }
+
+    #[test]
+    fn snap_to_pixels_rounds_scale_rotation_and_position() {
+        let region = (0.0, 0.0, 15.0, 9.0);
+        let snap = |scale: f32, rotation: f32| {
+            let transform = ImageTransform {
+                rotation,
+                ..transform(0.3, 0.2, scale, false)
+            };
+            snap_to_pixels(region, Orientation::NoTransforms, 64, &transform)
+        };
+
+        assert_eq!(snap(2.4, 0.0).scale, 2.0);
+        assert_close([snap(0.3, 0.0).scale], [1.0 / 3.0]);
+        assert_eq!(snap(1.0, 100.0).rotation, 90.0);
+
+        let snapped = snap(2.4, 0.0);
+        let affine = region_to_canvas(region, Orientation::NoTransforms, 64, &snapped);
+        assert_eq!(affine.c, affine.c.round());
+        assert_eq!(affine.f, affine.f.round());
+    }
+
+    #[test]
+    fn separable_and_2d_warps_agree() {
+        let source = RgbaImage::from_fn(37, 23, |x, y| {
+            let alpha = if (x + y) % 5 == 0 { 0 } else { 200 };
+            Rgba([(x * 7) as u8, (y * 11) as u8, (x * y) as u8, alpha])
+        });
+        let clip = (0.0, 0.0, 37.0, 23.0);
+        let affines = [
+            Affine::scale(3.3, 3.3).then(&Affine::translate(4.2, 7.9)),
+            Affine::scale(0.6, 0.6).then(&Affine::translate(2.0, 1.0)),
+            Affine::scale(-1.7, 1.7)
+                .then(&Affine::rotate(90.0))
+                .then(&Affine::translate(60.0, 3.0)),
+        ];
+
+        for filter in [
+            FilterType::Triangle,
+            FilterType::CatmullRom,
+            FilterType::Lanczos3,
+        ] {
+            for affine in &affines {
+                let inverse = affine.invert().unwrap();
+                let mapping = axis_mapping(&inverse, 96).unwrap();
+                let margin = kernel(filter).1 * affine.scale_factor().max(1.0);
+                let bounds = canvas_bounds(clip, affine, margin, 96).unwrap();
+                let samples = Samples::new(&source, false, clip);
+
+                let mut separable = RgbaImage::new(96, 96);
+                let mut full = RgbaImage::new(96, 96);
+                warp_separable(&samples, &inverse, mapping, bounds, filter, &mut separable);
+                warp_2d(&samples, &inverse, bounds, filter, &mut full);
+
+                let difference = separable
+                    .as_raw()
+                    .iter()
+                    .zip(full.as_raw())
+                    .map(|(a, b)| a.abs_diff(*b))
+                    .max();
+                assert!(
+                    difference <= Some(1),
+                    "{filter:?} {mapping:?}: {difference:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_by_other_angles_are_not_separable() {
+        let inverse = Affine::rotate(30.0).invert().unwrap();
+
+        assert_eq!(axis_mapping(&inverse, 512), None);
+        assert_eq!(
+            axis_mapping(&Affine::rotate(270.0), 512),
+            Some(AxisMapping::Swapped)
+        );
+    }
+
+    #[test]
+    fn warp_fades_partly_clipped_pixels() {
+        let source = RgbaImage::from_pixel(4, 1, Rgba([255, 255, 255, 255]));
+        let canvas = warp(
+            &source,
+            false,
+            (0.0, 0.0, 2.5, 1.0),
+            &Affine::translate(0.0, 0.0),
+            4,
+            FilterType::Nearest,
+        );
+
+        let alpha: Vec<u8> = (0..4).map(|x| canvas.get_pixel(x, 0)[3]).collect();
+        assert_eq!(alpha, [255, 255, 128, 0]);
+        assert_eq!(canvas.get_pixel(2, 0).0, [255, 255, 255, 128]);
+    }
+
+    #[test]
+    fn halving_premultiplies_the_colour() {
+        let image = RgbaImage::from_fn(3, 2, |x, _| {
+            if x == 0 {
+                Rgba([255, 0, 0, 255])
+            } else {
+                Rgba([0, 255, 0, 0])
+            }
+        });
+        let halved = halve(&image, true);
+
+        assert_eq!(halved.dimensions(), (2, 1));
+        assert_eq!(halved.get_pixel(0, 0).0, [128, 0, 0, 128]);
+        // the last column averages the pixels that exist
+        assert_eq!(halved.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    }
+}
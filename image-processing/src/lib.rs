@@ -1,21 +1,57 @@
+mod image_animation;
 mod image_border;
+mod image_color;
+mod image_export;
+mod image_framing;
+mod image_limits;
+mod image_mask;
+mod image_metadata;
 mod image_options;
+mod image_project;
+#[cfg(feature = "psd")]
+mod image_psd;
 mod image_shadow;
+mod image_source;
 mod image_stencil;
+mod image_transform;
 mod utils;
 
-use std::io::Cursor;
+use std::{collections::HashMap, io::Cursor};
 
-use image::{DynamicImage, GenericImageView, ImageBuffer, ImageReader, Rgba, imageops};
+use image::{DynamicImage, ImageBuffer, ImageDecoder, ImageReader, imageops, metadata::Orientation};
 use imageproc::rect::Rect;
-use wasm_bindgen::prelude::*;
+use tsify::Ts;
+use wasm_bindgen::{Clamped, prelude::*};
+use web_sys::{ImageData, OffscreenCanvasRenderingContext2d};
 
 use crate::{
+    image_animation::{AnimationFrame, animation_to_bytes, decode_animation, is_animated},
     image_border::ImageBorder,
-    image_options::{ImageDimensions, ImageRenderOptions, ImageTransform},
+    image_color::decode_to_srgb,
+    image_export::{
+        MANIFEST_FILE_NAME, ZipManifest, ZipManifestEntry, check_file_names, image_to_bytes,
+        images_to_zip,
+    },
+    image_framing::suggest_transform,
+    image_limits::ProcessingLimits,
+    image_mask::{decode_mask, feather_mask, resize_mask, with_vector_mask},
+    image_metadata::{EmbeddedSettings, read_embedded_settings, settings_to_xmp},
+    image_options::{
+        FramingPreset, ImageDimensions, ImageOrientation, ImageRenderOptions, ImageTransform,
+        NormalizedTransform, RingSubject, ZipExportOptions,
+    },
+    image_project::{TokenProject, load_project, save_project},
     image_shadow::{ImageShadow, ShadowOptions},
-    image_stencil::{overlay_images, ImageStencil},
-    utils::set_panic_hook,
+    image_source::{oriented_size, SourceImage},
+    image_stencil::{lerp_images, overlay_images, ImageStencil},
+    utils::{from_ts, into_ts, set_panic_hook},
+};
+#[cfg(feature = "svg")]
+use crate::image_source::is_svg;
+#[cfg(feature = "psd")]
+use crate::{
+    image_options::{PsdImportOptions, PsdLayers},
+    image_psd::{import_psd, read_psd_layers},
 };
 
 fn create_blank_image(dimensions: &ImageDimensions) -> DynamicImage {
@@ -23,20 +59,185 @@ fn create_blank_image(dimensions: &ImageDimensions) -> DynamicImage {
     DynamicImage::ImageRgba8(blank_image)
 }
 
-fn image_to_bytes(image: &DynamicImage) -> Result<Vec<u8>, JsValue> {
-    let mut bytes = Vec::new();
-    image
-        .write_to(
-            &mut std::io::Cursor::new(&mut bytes),
-            image::ImageFormat::WebP,
-        )
-        .map_err(|e| JsValue::from_str(&format!("Failed to write image: {e}")))?;
-    Ok(bytes)
+/// Create a reader for the image, guessing the format from its contents.
+fn image_reader(image_data: &[u8]) -> Result<ImageReader<Cursor<&[u8]>>, JsValue> {
+    #[allow(unused_mut)]
+    let mut reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| JsValue::from_str(&format!("Failed to read image: {e}")))?;
+
+    // TGA files have no magic bytes, so unknown data is only tried as TGA if
+    // its header is plausible, anything else fails as an unsupported format
+    #[cfg(feature = "tga")]
+    if reader.format().is_none() && looks_like_tga(image_data) {
+        reader.set_format(image::ImageFormat::Tga);
+    }
+
+    Ok(reader)
+}
+
+/// Create a decoder for the image that has only read the header, without
+/// any limits. For reading the size and metadata, decoding the pixels needs
+/// [`limited_decoder`].
+fn header_decoder(image_data: &[u8]) -> Result<impl ImageDecoder + '_, JsValue> {
+    let mut reader = image_reader(image_data)?;
+    // our own limits are applied by `limited_decoder`, with better errors
+    reader.no_limits();
+
+    reader
+        .into_decoder()
+        .map_err(|e| JsValue::from_str(&format!("Failed to decode image: {e}")))
+}
+
+/// Create a decoder for the image that decodes the pixels within the limits.
+fn limited_decoder<'a>(
+    image_data: &'a [u8],
+    limits: &ProcessingLimits,
+) -> Result<impl ImageDecoder + 'a, JsValue> {
+    let mut decoder = header_decoder(image_data)?;
+    limits.apply(&mut decoder)?;
+
+    Ok(decoder)
+}
+
+/// Decode the image, converting it to sRGB if it has an embedded ICC
+/// profile. The pixels are returned as they are stored, together with the
+/// EXIF orientation.
+fn read_image(
+    image_data: &[u8],
+    limits: &ProcessingLimits,
+) -> Result<(SourceImage, Orientation), JsValue> {
+    #[cfg(feature = "svg")]
+    if is_svg(image_data) {
+        return Ok((SourceImage::from_svg(image_data)?, Orientation::NoTransforms));
+    }
+
+    let mut decoder = limited_decoder(image_data, limits)?;
+    let orientation = decoder
+        .orientation()
+        .map_err(|e| JsValue::from_str(&format!("Failed to read image orientation: {e}")))?;
+
+    let image = decode_to_srgb(decoder).map_err(|e| limits.decode_error(e))?;
+
+    Ok((image.into(), orientation))
+}
+
+/// Decode an image within the limits, as it is stored and without any color
+/// conversion. For masks and ring sprite sheets.
+fn decode_limited(image_data: &[u8], limits: &ProcessingLimits) -> Result<DynamicImage, JsValue> {
+    let decoder = limited_decoder(image_data, limits)?;
+
+    DynamicImage::from_decoder(decoder).map_err(|e| limits.decode_error(e))
+}
+
+/// Decode the image and turn it upright.
+fn decode_image(
+    image_data: &[u8],
+    options: &ImageRenderOptions,
+    limits: &ProcessingLimits,
+) -> Result<SourceImage, JsValue> {
+    let (image, orientation) = read_image(image_data, limits)?;
+
+    Ok(orient_image(image, orientation, options))
+}
+
+/// The size of the image with the orientation applied, read from the header
+/// without decoding the pixels.
+fn read_dimensions(image_data: &[u8], options: &ImageRenderOptions) -> Result<(u32, u32), JsValue> {
+    #[cfg(feature = "svg")]
+    if is_svg(image_data) {
+        let image = SourceImage::from_svg(image_data)?;
+        return Ok(orient_image(image, Orientation::NoTransforms, options).oriented_dimensions());
+    }
+
+    let mut decoder = header_decoder(image_data)?;
+    let (width, height) = decoder.dimensions();
+    let orientation = match options.orientation {
+        Some(orientation) => orientation.into(),
+        None => decoder
+            .orientation()
+            .map_err(|e| JsValue::from_str(&format!("Failed to read image orientation: {e}")))?,
+    };
+
+    Ok(oriented_size(width, height, orientation))
+}
+
+/// Apply the orientation override from the options, or the EXIF orientation
+/// if there is none.
+fn orient_image(
+    image: SourceImage,
+    exif_orientation: Orientation,
+    options: &ImageRenderOptions,
+) -> SourceImage {
+    let orientation = options
+        .orientation
+        .map(Orientation::from)
+        .unwrap_or(exif_orientation);
+
+    image.oriented(orientation)
+}
+
+/// Check the fields of a TGA header that only allow a few values: the color
+/// map type, the image type, the size and the pixel depth.
+#[cfg(feature = "tga")]
+fn looks_like_tga(data: &[u8]) -> bool {
+    let Some(header) = data.get(..18) else {
+        return false;
+    };
+    let width = u16::from_le_bytes([header[12], header[13]]);
+    let height = u16::from_le_bytes([header[14], header[15]]);
+
+    matches!(header[1], 0 | 1)
+        && matches!(header[2], 1 | 2 | 3 | 9 | 10 | 11)
+        && width != 0
+        && height != 0
+        && matches!(header[16], 8 | 15 | 16 | 24 | 32)
+}
+
+/// Turn the rendered image into an `ImageData` that can be drawn onto a
+/// canvas.
+fn to_image_data(image: DynamicImage) -> Result<ImageData, JsValue> {
+    let image = image.into_rgba8();
+
+    ImageData::new_with_u8_clamped_array_and_sh(
+        Clamped(image.as_raw()),
+        image.width(),
+        image.height(),
+    )
+}
+
+/// Encode the rendered image according to the export options, embedding the
+/// render settings if requested.
+fn encode_render(
+    image: &DynamicImage,
+    options: &ImageRenderOptions,
+    mask: &DynamicImage,
+) -> Result<Vec<u8>, JsValue> {
+    let export = options.export.clone().unwrap_or_default();
+    let xmp = settings_xmp(options, mask)?;
+
+    image_to_bytes(image, &export, xmp.as_deref())
+}
+
+/// The XMP packet with the render settings, if the export options ask for
+/// it.
+fn settings_xmp(
+    options: &ImageRenderOptions,
+    mask: &DynamicImage,
+) -> Result<Option<String>, JsValue> {
+    match &options.export {
+        Some(export) if export.embed_settings => Ok(Some(settings_to_xmp(options, mask)?)),
+        _ => Ok(None),
+    }
 }
 
 #[wasm_bindgen]
 pub struct ImageProcessor {
     border: Option<ImageBorder>,
+    /// Source images that were decoded once with `load_source`, by handle.
+    sources: HashMap<u32, SourceImage>,
+    next_source_handle: u32,
+    limits: ProcessingLimits,
 }
 
 #[wasm_bindgen]
@@ -44,8 +245,22 @@ impl ImageProcessor {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Result<Self, JsValue> {
         set_panic_hook();
+        // the image crate has no JPEG XL decoder, so we plug one in, once for
+        // all processors
+        #[cfg(feature = "jxl")]
+        {
+            static REGISTER_JXL: std::sync::Once = std::sync::Once::new();
+            REGISTER_JXL.call_once(|| {
+                jxl_oxide::integration::register_image_decoding_hook();
+            });
+        }
 
-        Ok(ImageProcessor { border: None })
+        Ok(ImageProcessor {
+            border: None,
+            sources: HashMap::new(),
+            next_source_handle: 0,
+            limits: ProcessingLimits::default(),
+        })
     }
 
     pub fn render(
@@ -54,71 +269,651 @@ impl ImageProcessor {
         mask_data: Option<Vec<u8>>,
         options: ImageRenderOptions,
     ) -> Result<Vec<u8>, JsValue> {
-        // Load the image data into a DynamicImage
-        let image: DynamicImage = ImageReader::new(Cursor::new(image_data))
-            .with_guessed_format()
-            .map_err(|e| JsValue::from_str(&format!("Failed to read image: {e}")))?
-            .decode()
-            .map_err(|e| JsValue::from_str(&format!("Failed to decode image: {e}")))?;
-
-        let mask: DynamicImage = match mask_data {
-            Some(x) => {
-                ImageBuffer::<Rgba<u8>, Vec<u8>>::from_vec(options.dimensions.size, options.dimensions.size, x)
-                    .ok_or(JsValue::from_str(
-                        "Failed to create mask image from provided data",
-                    ))?
-                    .into()
-            }
-            None => create_blank_image(&options.dimensions),
-        };
+        self.limits.check_canvas(&options.dimensions)?;
+        let image = decode_image(image_data, &options, &self.limits)?;
+        let mask = decode_mask(mask_data, &options, &self.limits)?;
+        let composite_image = self.render_decoded(&image, &mask, &options)?;
 
-        let image = self.cut_and_transform(
-            image,
+        encode_render(&composite_image, &options, &mask)
+    }
+
+    /// Render the image without encoding it, returning the raw RGBA pixels.
+    /// Meant for interactive previews, where the encode and decode round trip
+    /// would be wasted work.
+    pub fn render_rgba(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<u8>, JsValue> {
+        let composite_image = self.render_image(image_data, mask_data, &options)?;
+
+        Ok(composite_image.into_rgba8().into_raw())
+    }
+
+    /// Render the image into an `ImageData` that can be drawn directly onto a
+    /// canvas.
+    pub fn render_image_data(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<ImageData, JsValue> {
+        let composite_image = self.render_image(image_data, mask_data, &options)?;
+
+        to_image_data(composite_image)
+    }
+
+    /// Render the image and draw it straight onto the given canvas context at
+    /// the top left corner.
+    pub fn render_to_canvas(
+        &self,
+        context: &OffscreenCanvasRenderingContext2d,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<(), JsValue> {
+        let image_data = self.render_image_data(image_data, mask_data, options)?;
+
+        context.put_image_data(&image_data, 0.0, 0.0)
+    }
+
+    /// Decode the image once and keep it, so that it can be rendered
+    /// repeatedly with the `render_source` methods without decoding it again.
+    /// Returns the handle of the source.
+    pub fn load_source(&mut self, image_data: &[u8]) -> Result<u32, JsValue> {
+        let (image, orientation) = read_image(image_data, &self.limits)?;
+
+        let handle = self.next_source_handle;
+        self.next_source_handle += 1;
+        self.sources.insert(handle, image.oriented(orientation));
+
+        Ok(handle)
+    }
+
+    /// Free a source loaded with [`Self::load_source`].
+    pub fn unload_source(&mut self, handle: u32) {
+        self.sources.remove(&handle);
+    }
+
+    /// Like [`Self::render`], but for a source loaded with
+    /// [`Self::load_source`].
+    pub fn render_source(
+        &self,
+        handle: u32,
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<u8>, JsValue> {
+        let (composite_image, mask) = self.render_source_image(handle, mask_data, &options)?;
+
+        encode_render(&composite_image, &options, &mask)
+    }
+
+    /// Like [`Self::render_rgba`], but for a source loaded with
+    /// [`Self::load_source`].
+    pub fn render_source_rgba(
+        &self,
+        handle: u32,
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<u8>, JsValue> {
+        let (composite_image, _) = self.render_source_image(handle, mask_data, &options)?;
+
+        Ok(composite_image.into_rgba8().into_raw())
+    }
+
+    /// Like [`Self::render_image_data`], but for a source loaded with
+    /// [`Self::load_source`].
+    pub fn render_source_image_data(
+        &self,
+        handle: u32,
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<ImageData, JsValue> {
+        let (composite_image, _) = self.render_source_image(handle, mask_data, &options)?;
+
+        to_image_data(composite_image)
+    }
+
+    /// Replace the limits that protect against images that would exhaust the
+    /// memory. Exceeding a limit throws a `LimitError`.
+    pub fn set_limits(&mut self, limits: Ts<ProcessingLimits>) -> Result<(), JsValue> {
+        self.limits = from_ts(limits)?;
+
+        Ok(())
+    }
+
+    /// The orientation stored in the EXIF data of the image.
+    /// It is applied automatically when rendering, unless it is overridden
+    /// in the render options.
+    pub fn image_orientation(&self, image_data: &[u8]) -> Result<Ts<ImageOrientation>, JsValue> {
+        let mut decoder = header_decoder(image_data)?;
+        let orientation = decoder
+            .orientation()
+            .map_err(|e| JsValue::from_str(&format!("Failed to read image orientation: {e}")))?;
+
+        into_ts(&ImageOrientation::from(orientation))
+    }
+
+    /// Suggest a transform that fits the subject of a source loaded with
+    /// [`Self::load_source`] into the stencil circle of the render options,
+    /// based on where the opaque pixels are. The flip and crop of the current
+    /// transform are kept, the rotation is reset.
+    pub fn suggest_source_transform(
+        &self,
+        handle: u32,
+        options: ImageRenderOptions,
+        preset: Ts<FramingPreset>,
+    ) -> Result<ImageTransform, JsValue> {
+        let image = self.get_source(handle, &options)?;
+
+        Ok(suggest_transform(
+            &image,
             &options.dimensions,
+            from_ts(preset)?,
             &options.transform,
-        );
+        ))
+    }
+
+    /// Turn the transform of the render options into one relative to the
+    /// canvas and source size, which gives the same framing at any output
+    /// size and source resolution.
+    pub fn normalize_transform(
+        &self,
+        image_data: &[u8],
+        options: ImageRenderOptions,
+    ) -> Result<Ts<NormalizedTransform>, JsValue> {
+        let dimensions = read_dimensions(image_data, &options)?;
+
+        into_ts(&options.transform.normalized(options.dimensions.size, dimensions))
+    }
+
+    /// Turn a normalized transform back into one for the dimensions of the
+    /// render options and the size of the image.
+    pub fn denormalize_transform(
+        &self,
+        image_data: &[u8],
+        transform: Ts<NormalizedTransform>,
+        options: ImageRenderOptions,
+    ) -> Result<ImageTransform, JsValue> {
+        let dimensions = read_dimensions(image_data, &options)?;
+
+        Ok(from_ts(transform)?.to_transform(options.dimensions.size, dimensions))
+    }
+
+    /// Like [`Self::normalize_transform`], but for a source loaded with
+    /// [`Self::load_source`].
+    pub fn normalize_source_transform(
+        &self,
+        handle: u32,
+        options: ImageRenderOptions,
+    ) -> Result<Ts<NormalizedTransform>, JsValue> {
+        let image = self.get_source(handle, &options)?;
+
+        into_ts(
+            &options
+                .transform
+                .normalized(options.dimensions.size, image.oriented_dimensions()),
+        )
+    }
+
+    /// Like [`Self::denormalize_transform`], but for a source loaded with
+    /// [`Self::load_source`].
+    pub fn denormalize_source_transform(
+        &self,
+        handle: u32,
+        transform: Ts<NormalizedTransform>,
+        options: ImageRenderOptions,
+    ) -> Result<ImageTransform, JsValue> {
+        let image = self.get_source(handle, &options)?;
+
+        Ok(from_ts(transform)?.to_transform(options.dimensions.size, image.oriented_dimensions()))
+    }
+
+    /// Whether the image is an animated GIF, PNG or WebP.
+    pub fn is_animated(&self, image_data: &[u8]) -> Result<bool, JsValue> {
+        Ok(is_animated(image_data))
+    }
+
+    /// Render every frame of an animated image and encode the result as an
+    /// animated PNG or WebP, keeping the original frame timings.
+    /// Images that are not animated are rendered like in [`Self::render`].
+    pub fn render_animated(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<u8>, JsValue> {
+        self.limits.check_canvas(&options.dimensions)?;
+        let Some(animation) = decode_animation(image_data, &self.limits)? else {
+            return self.render(image_data, mask_data, options);
+        };
+        self.limits
+            .check_animation(&options.dimensions, animation.frames.len())?;
+        let mask = decode_mask(mask_data, &options, &self.limits)?;
+
+        let frames = animation
+            .frames
+            .into_iter()
+            .map(|frame| {
+                let image = orient_image(frame.image.into(), animation.orientation, &options);
+                Ok(AnimationFrame {
+                    image: self.render_decoded(&image, &mask, &options)?,
+                    delay_ms: frame.delay_ms,
+                })
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?;
+
+        let xmp = settings_xmp(&options, &mask)?;
+        animation_to_bytes(
+            &frames,
+            &options.export.clone().unwrap_or_default(),
+            xmp.as_deref(),
+        )
+    }
+
+    /// Render only the subject for Foundry's dynamic token ring, together
+    /// with the matching `prototypeToken.ring.subject` values.
+    /// The texture is passed through as the path the subject will be stored
+    /// at.
+    pub fn render_subject(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+        texture: Option<String>,
+    ) -> Result<SubjectRender, JsValue> {
+        let options = ImageRenderOptions {
+            subject_only: true,
+            ..options
+        };
+
+        self.limits.check_canvas(&options.dimensions)?;
+        let image = decode_image(image_data, &options, &self.limits)?;
+        let mask = decode_mask(mask_data, &options, &self.limits)?;
+        let composite_image = self.render_decoded(&image, &mask, &options)?;
+        let subject = RingSubject {
+            scale: self.subject_scale(&options)?,
+            texture,
+        };
+
+        Ok(SubjectRender {
+            image: encode_render(&composite_image, &options, &mask)?,
+            subject,
+        })
+    }
+
+    /// Render the token once for every grid target in the loaded ring sprite
+    /// sheet, each using the ring frame for that grid target.
+    /// The transform, stencil radius and mask are scaled along with the
+    /// token size.
+    pub fn render_grid_sizes(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<GridSizeRender>, JsValue> {
+        let Some(border) = &self.border else {
+            return Err(JsValue::from_str("No ring border loaded"));
+        };
+
+        self.limits.check_canvas(&options.dimensions)?;
+        let image = decode_image(image_data, &options, &self.limits)?;
+        let mask = decode_mask(mask_data, &options, &self.limits)?;
+
+        border
+            .get_grid_targets()
+            .into_iter()
+            .map(|(grid_target, token_size)| {
+                let sized_options = ImageRenderOptions {
+                    grid_target: Some(grid_target),
+                    ..options.with_token_size(token_size)
+                };
+                let dimensions = sized_options.dimensions.clone();
+                self.limits.check_canvas(&dimensions)?;
+                let mask = resize_mask(&mask, &dimensions);
+
+                let composite_image = self.render_decoded(&image, &mask, &sized_options)?;
+
+                Ok(GridSizeRender {
+                    grid_target,
+                    dimensions,
+                    image: encode_render(&composite_image, &sized_options, &mask)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Render several variants of the same image and bundle them into a ZIP
+    /// archive, together with a `manifest.json` that records the render
+    /// options and ring of every file.
+    /// The mask is given in the format and size of the first entry and
+    /// resized for the others.
+    pub fn render_zip(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: Ts<ZipExportOptions>,
+    ) -> Result<Vec<u8>, JsValue> {
+        let options = from_ts(options)?;
+        for entry in &options.entries {
+            self.limits.check_canvas(&entry.options.dimensions)?;
+        }
+        let file_names: Vec<String> = options
+            .entries
+            .iter()
+            .map(|entry| {
+                let export = entry.options.export.clone().unwrap_or_default();
+                format!("{}.{}", entry.name, export.format.extension())
+            })
+            .collect();
+        check_file_names(
+            file_names
+                .iter()
+                .map(String::as_str)
+                .chain([MANIFEST_FILE_NAME]),
+        )?;
+
+        let Some(first) = options.entries.first() else {
+            return images_to_zip(&[], &ZipManifest { files: Vec::new() });
+        };
+        let (image, orientation) = read_image(image_data, &self.limits)?;
+        let mask = decode_mask(mask_data, &first.options, &self.limits)?;
+
+        let mut files = Vec::with_capacity(options.entries.len());
+        let mut manifest = ZipManifest { files: Vec::with_capacity(options.entries.len()) };
+
+        for (entry, file) in options.entries.into_iter().zip(file_names) {
+            let mask = resize_mask(&mask, &entry.options.dimensions);
+            let image = orient_image(image.clone(), orientation, &entry.options);
+            let composite_image = self.render_decoded(&image, &mask, &entry.options)?;
+
+            let ring = match &self.border {
+                Some(border) if entry.options.ring || entry.options.subject_only => Some(
+                    border.get_ring_info(&entry.options.dimensions, entry.options.grid_target)?,
+                ),
+                _ => None,
+            };
+
+            files.push((file.clone(), encode_render(&composite_image, &entry.options, &mask)?));
+            manifest.files.push(ZipManifestEntry {
+                file,
+                options: entry.options,
+                ring,
+            });
+        }
+
+        images_to_zip(&files, &manifest)
+    }
+
+    /// List the layers of a PSD file, so that each of them can be assigned a
+    /// role for [`Self::render_psd`].
+    #[cfg(feature = "psd")]
+    pub fn psd_layers(&self, psd_data: &[u8]) -> Result<Ts<PsdLayers>, JsValue> {
+        into_ts(&read_psd_layers(psd_data, &self.limits)?)
+    }
+
+    /// Render a layered PSD file. Subject and pop-out layers are flattened
+    /// into the token image, discarded layers are dropped.
+    /// The alpha of the pop-out layers is added to the mask, after it was
+    /// moved into place with the same transform as the image.
+    #[cfg(feature = "psd")]
+    pub fn render_psd(
+        &self,
+        psd_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        psd_options: Ts<PsdImportOptions>,
+        options: ImageRenderOptions,
+    ) -> Result<Vec<u8>, JsValue> {
+        self.limits.check_canvas(&options.dimensions)?;
+        let psd = import_psd(psd_data, &from_ts(psd_options)?, &self.limits)?;
+        let mut mask = decode_mask(mask_data, &options, &self.limits)?.into_rgba8();
+
+        if let Some(pop_out) = psd.mask {
+            let pop_out = self
+                .cut_and_transform(
+                    &pop_out.into(),
+                    &options.dimensions,
+                    &options.transform,
+                    options.filter(),
+                )?
+                .into_rgba8();
+
+            for (pixel, pop_out_pixel) in mask.pixels_mut().zip(pop_out.pixels()) {
+                if pop_out_pixel[3] > pixel[3] {
+                    *pixel = *pop_out_pixel;
+                }
+            }
+        }
+
+        let mask = DynamicImage::ImageRgba8(mask);
+        let composite_image = self.render_decoded(&psd.image.into(), &mask, &options)?;
+
+        encode_render(&composite_image, &options, &mask)
+    }
+
+    /// Read the render settings back from a token that was exported with
+    /// embedded settings.
+    pub fn read_settings(&self, image_data: &[u8]) -> Result<Option<EmbeddedSettings>, JsValue> {
+        read_embedded_settings(image_data, &self.limits)
+    }
+
+    /// Save the whole editing session into a single project file.
+    /// The currently loaded ring is included if `include_ring` is set.
+    pub fn save_project(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: ImageRenderOptions,
+        include_ring: bool,
+    ) -> Result<Vec<u8>, JsValue> {
+        self.limits.check_canvas(&options.dimensions)?;
+        let mask = match mask_data {
+            Some(x) => Some(decode_mask(Some(x), &options, &self.limits)?),
+            None => None,
+        };
+        let ring = match &self.border {
+            Some(border) if include_ring => Some(border.get_source()?),
+            _ => None,
+        };
+        let ring = ring
+            .as_ref()
+            .map(|(image, config)| (image.as_slice(), config.as_str()));
+
+        save_project(image_data, mask.as_ref(), &options, ring)
+    }
+
+    /// Load a project file. If the project contains a ring, it is loaded as
+    /// the current border.
+    pub fn load_project(&mut self, project_data: &[u8]) -> Result<TokenProject, JsValue> {
+        let project = load_project(project_data, &self.limits)?;
 
-        let composite_image = self.build_image(&image, &mask, &options)?;
+        if let Some((ring_image, ring_config)) = project.get_ring() {
+            self.border = Some(ImageBorder::from_js(
+                ring_image,
+                ring_config.to_string(),
+                &self.limits,
+            )?);
+        }
 
-        image_to_bytes(&composite_image)
+        Ok(project)
     }
 
     pub fn load_border(&mut self, image_data: &[u8], meta: String) -> Result<(), JsValue> {
-        self.border = Some(ImageBorder::from_js(image_data, meta)?);
+        self.border = Some(ImageBorder::from_js(image_data, meta, &self.limits)?);
 
         Ok(())
     }
 }
 
+#[wasm_bindgen]
+pub struct SubjectRender {
+    image: Vec<u8>,
+    subject: RingSubject,
+}
+
+#[wasm_bindgen]
+impl SubjectRender {
+    #[wasm_bindgen(getter)]
+    pub fn image(&self) -> Vec<u8> {
+        self.image.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn subject(&self) -> Result<Ts<RingSubject>, JsValue> {
+        into_ts(&self.subject)
+    }
+}
+
+#[wasm_bindgen]
+pub struct GridSizeRender {
+    grid_target: u32,
+    dimensions: ImageDimensions,
+    image: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl GridSizeRender {
+    #[wasm_bindgen(getter)]
+    pub fn grid_target(&self) -> u32 {
+        self.grid_target
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn dimensions(&self) -> ImageDimensions {
+        self.dimensions.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn image(&self) -> Vec<u8> {
+        self.image.clone()
+    }
+}
+
 impl ImageProcessor {
-    /// Cut the image to fit into the given dimensions, centering it and applying the given image transform.
-    pub fn cut_and_transform(&self, image: DynamicImage, dimensions: &ImageDimensions, image_transform: &ImageTransform) -> DynamicImage {
-        // first flip if needed
-        let image = if image_transform.flipped {
-            image.fliph()
-        } else {
-            image
-        };
+    /// Decode the image and mask and render the composite image.
+    fn render_image(
+        &self,
+        image_data: &[u8],
+        mask_data: Option<Vec<u8>>,
+        options: &ImageRenderOptions,
+    ) -> Result<DynamicImage, JsValue> {
+        self.limits.check_canvas(&options.dimensions)?;
+        let image = decode_image(image_data, options, &self.limits)?;
+        let mask = decode_mask(mask_data, options, &self.limits)?;
 
-        // second scale
-        let scaled_width = (image.width() as f32 * image_transform.scale) as u32;
-        let scaled_height = (image.height() as f32 * image_transform.scale) as u32;
+        self.render_decoded(&image, &mask, options)
+    }
 
-        let image= image.resize(
-            scaled_width,
-            scaled_height,
-            imageops::FilterType::CatmullRom,
-        );
+    /// Render a source loaded with [`Self::load_source`], without encoding
+    /// it. The decoded mask is returned with it, for embedding the settings.
+    fn render_source_image(
+        &self,
+        handle: u32,
+        mask_data: Option<Vec<u8>>,
+        options: &ImageRenderOptions,
+    ) -> Result<(DynamicImage, DynamicImage), JsValue> {
+        self.limits.check_canvas(&options.dimensions)?;
+        let image = self.get_source(handle, options)?;
+        let mask = decode_mask(mask_data, options, &self.limits)?;
+        let composite_image = self.render_decoded(&image, &mask, options)?;
 
-        // then calculate the offsets
-        let x_offset = (dimensions.size as i32 - image.width() as i32) / 2 + image_transform.pos_x;
-        let y_offset = (dimensions.size as i32 - image.height() as i32) / 2 + image_transform.pos_y;
+        Ok((composite_image, mask))
+    }
 
-        // finally, cut the image by overlaying it on a blank canvas of the right size
-        let mut tmp_image = create_blank_image(dimensions);
-        imageops::overlay(&mut tmp_image, &image, x_offset as i64, y_offset as i64);
+    /// A loaded source with the orientation override from the options
+    /// applied.
+    fn get_source(
+        &self,
+        handle: u32,
+        options: &ImageRenderOptions,
+    ) -> Result<SourceImage, JsValue> {
+        let source = self
+            .sources
+            .get(&handle)
+            .ok_or_else(|| JsValue::from_str(&format!("No source loaded for handle {handle}")))?
+            .clone();
 
-        tmp_image
+        Ok(match options.orientation {
+            Some(orientation) => source.oriented(orientation.into()),
+            None => source,
+        })
+    }
+
+    /// Render the composite image from an already decoded image and mask.
+    /// Preview quality renders at a reduced resolution and upscales the
+    /// result. The vector mask is drawn at the resolution that is rendered.
+    fn render_decoded(
+        &self,
+        image: &SourceImage,
+        mask: &DynamicImage,
+        options: &ImageRenderOptions,
+    ) -> Result<DynamicImage, JsValue> {
+        let factor = options.quality.resolution_factor();
+        if factor < 1.0 {
+            let token_size = (options.dimensions.token_size() as f32 * factor).round().max(1.0);
+            let preview_options = options.with_token_size(token_size as u32);
+            let preview_mask = resize_mask(mask, &preview_options.dimensions);
+            let preview_mask = with_vector_mask(&preview_mask, options, &self.limits)?;
+
+            let image = self.cut_and_transform(
+                image,
+                &preview_options.dimensions,
+                &preview_options.transform,
+                options.filter(),
+            )?;
+            let preview = self.build_image(&image, &preview_mask, &preview_options)?;
+
+            return Ok(preview.resize_exact(
+                options.dimensions.size,
+                options.dimensions.size,
+                options.filter(),
+            ));
+        }
+
+        let image = self.cut_and_transform(
+            image,
+            &options.dimensions,
+            &options.transform,
+            options.filter(),
+        )?;
+        let mask = with_vector_mask(mask, options, &self.limits)?;
+
+        self.build_image(&image, &mask, options)
+    }
+
+    /// The subject scale correction for Foundry's dynamic token ring, so that
+    /// the stencil circle lines up with the inside of the ring.
+    fn subject_scale(&self, options: &ImageRenderOptions) -> Result<f32, JsValue> {
+        let dimensions = &options.dimensions;
+        if dimensions.stencil_radius == 0 {
+            return Err(JsValue::from_str("Stencil radius must be greater than 0"));
+        }
+
+        let inner_radius = match &self.border {
+            Some(border) => border.get_inner_radius(dimensions, options.grid_target)?,
+            // the default ring starts right at the stencil radius
+            None => dimensions.stencil_radius as f32 / (dimensions.token_size() as f32 / 2.0),
+        };
+
+        Ok(inner_radius * dimensions.size as f32 / (2.0 * dimensions.stencil_radius as f32))
+    }
+
+    /// Cut the image to fit into the given dimensions, centering it and applying the given image transform.
+    pub fn cut_and_transform(
+        &self,
+        image: &SourceImage,
+        dimensions: &ImageDimensions,
+        image_transform: &ImageTransform,
+        filter: imageops::FilterType,
+    ) -> Result<DynamicImage, JsValue> {
+        self.limits.check_canvas(dimensions)?;
+
+        // scale, flip, rotate and move the image in one go, only the part
+        // that ends up on the canvas is resampled
+        let image = image.draw(dimensions.size, image_transform, filter);
+
+        Ok(DynamicImage::ImageRgba8(image))
     }
 
     pub fn create_ring_image(
@@ -153,23 +948,16 @@ impl ImageProcessor {
         options: &ImageRenderOptions,
     ) -> Result<DynamicImage, JsValue> {
         let circle_mask = self.create_stencil(&options.dimensions);
-        let circle_mask_inverted = self.create_inverted_stencil(&options.dimensions);
         // circle mask that only keeps the center circle
-        let circle_stencil = circle_mask.to_stencil(0);
-        // inverted circle mask that keeps everything outside the center circle
-        // let circle_stencil_inverted = circle_mask.to_inverted_stencil();
-
-        let mask_stencil = mask.to_stencil(0);
-        let mask_stencil_inverted = mask.to_inverted_stencil(0);
-        let masked_image = image.stencil(&mask_stencil);
-        let masked_image_inverted = image.stencil_and(&[&mask_stencil_inverted, &circle_stencil]);
+        let circle_stencil = circle_mask.to_soft_stencil();
 
-        let (ring_bg, ring_fg) = if options.ring {
+        let (ring_bg, ring_fg) = if options.ring && !options.subject_only {
             if let Some(border) = &self.border {
-                border.get_ring(&options.dimensions)?
+                border.get_ring(&options.dimensions, options.grid_target, options.filter())?
             } else {
                 // If no border is loaded, create a default ring image
-                self.create_ring_image(&options.dimensions, 20)
+                let ring_width = 20.0 * options.quality.resolution_factor();
+                self.create_ring_image(&options.dimensions, ring_width.round() as u32)
             }
         } else {
             (
@@ -178,28 +966,49 @@ impl ImageProcessor {
             )
         };
 
-        let image_shadow_options = ShadowOptions::new_black(0.4, 3.0, 5, 5);
+        let image_shadow_options =
+            ShadowOptions::new_black(0.4, 3.0, 5, 5).for_render(options);
         let image_shadow = image.to_shadow(&image_shadow_options);
-        let image_shadow_mask = image_shadow.stencil(&mask_stencil);
-        let image_shadow_non_mask =
-            image_shadow.stencil_and(&[&mask_stencil_inverted, &circle_stencil]);
 
-        let ring_shadow_options = ShadowOptions::new_black(0.8, 10.0, 7, 12);
-        let ring_shadow = circle_mask_inverted.to_shadow(&ring_shadow_options);
-        let stenciled_ring_shadow = ring_shadow.stencil(&circle_stencil);
+        // Foundry draws the ring shadow itself when only the subject is rendered
+        let stenciled_ring_shadow = if options.subject_only {
+            create_blank_image(&options.dimensions)
+        } else {
+            let circle_mask_inverted = self.create_inverted_stencil(&options.dimensions);
+            let ring_shadow_options =
+                ShadowOptions::new_black(0.8, 10.0, 7, 12).for_render(options);
+            let ring_shadow = circle_mask_inverted.to_shadow(&ring_shadow_options);
+            ring_shadow.stencil(&circle_stencil)
+        };
 
-        Ok(overlay_images(
+        // the token as it looks where nothing is masked, with the image
+        // inside the ring, and where everything is masked, with the image
+        // popping out over the ring
+        let inside = overlay_images(
+            &options.dimensions,
+            &[
+                &ring_bg,                               // the ring image background
+                &ring_fg,                               // the ring image foreground
+                &image_shadow.stencil(&circle_stencil), // the image shadow in the circle area
+                &image.stencil(&circle_stencil),        // the image in the circle area
+                &stenciled_ring_shadow,                 // the ring shadow in the circle area
+            ],
+        );
+        let popped_out = overlay_images(
             &options.dimensions,
             &[
                 &ring_bg,               // the ring image background
                 &ring_fg,               // the ring image foreground
-                &image_shadow_non_mask, // the image shadow everywhere except the masked area
-                &masked_image_inverted, // the image in the circle area except the masked area
                 &stenciled_ring_shadow, // the ring shadow in the circle area
-                &image_shadow_mask,     // the image shadow in the masked area
-                &masked_image,          // the image in the masked area
+                &image_shadow,          // the image shadow everywhere
+                image,                  // the image everywhere
             ],
-        ))
+        );
+
+        // the mask alpha is used as coverage, so soft mask edges blend the
+        // popped out parts into the content inside the circle without a seam
+        let mask = feather_mask(mask, options.mask_feather);
+        Ok(lerp_images(&inside, &popped_out, &mask.to_soft_stencil()))
     }
 
     pub fn mask_and_stencil_image(
@@ -208,30 +1017,22 @@ impl ImageProcessor {
         mask: &DynamicImage,
         dimensions: &ImageDimensions,
     ) -> DynamicImage {
-        ImageBuffer::from_fn(dimensions.size, dimensions.size, |x, y| {
-            let distance_to_center = (x as i32 - (dimensions.size / 2) as i32).pow(2)
-                + (y as i32 - (dimensions.size / 2) as i32).pow(2);
-            let in_stencil_area = distance_to_center <= dimensions.stencil_radius.pow(2) as i32;
-            let is_masked = mask.get_pixel(x, y).0[3] > 0;
+        let circle_mask = self.create_stencil(dimensions);
 
-            if in_stencil_area || is_masked {
-                image.get_pixel(x, y)
-            } else {
-                image::Rgba([0, 0, 0, 0]) // Transparent pixel
-            }
-        })
-        .into()
+        image.stencil_or(&[&circle_mask.to_soft_stencil(), &mask.to_soft_stencil()])
     }
 
+    /// The stencil circle with an anti-aliased edge.
     pub fn create_stencil(&self, dimensions: &ImageDimensions) -> DynamicImage {
-        let mut stencil_image = create_blank_image(dimensions);
-        imageproc::drawing::draw_filled_circle_mut(
-            &mut stencil_image,
-            dimensions.center_tuple_i32(),
-            dimensions.stencil_radius as i32,
-            image::Rgba([255, 255, 255, 255]),
-        );
-        stencil_image
+        let (center_x, center_y) = dimensions.center_tuple_i32();
+        let radius = dimensions.stencil_radius as f32;
+
+        ImageBuffer::from_fn(dimensions.size, dimensions.size, |x, y| {
+            let distance = ((x as i32 - center_x) as f32).hypot((y as i32 - center_y) as f32);
+            let coverage = (radius + 0.5 - distance).clamp(0.0, 1.0);
+            image::Rgba([255, 255, 255, (coverage * 255.0).round() as u8])
+        })
+        .into()
     }
 
     pub fn create_inverted_stencil(&self, dimensions: &ImageDimensions) -> DynamicImage {
@@ -250,3 +1051,126 @@ impl ImageProcessor {
         stencil_image
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(orientation: Option<ImageOrientation>) -> ImageRenderOptions {
+        ImageRenderOptions {
+            orientation,
+            ..serde_json::from_str(
+                r#"{
+                    "transform": { "pos_x": 0, "pos_y": 0, "scale": 1, "flipped": false },
+                    "dimensions": { "size": 8, "oversized": false, "stencil_radius": 4 },
+                    "ring": false
+                }"#,
+            )
+            .unwrap()
+        }
+    }
+
+    /// A 2x1 PNG, animated if there are several frames, with the EXIF
+    /// orientation "rotate 90 degrees clockwise".
+    fn rotated_png(frames: u32) -> Vec<u8> {
+        let exif = [
+            b'M', b'M', 0, 42, 0, 0, 0, 8, // big-endian TIFF header
+            0, 1, // a single IFD entry
+            0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, // the orientation as a short
+            0, 0, 0, 0, // no further IFD
+        ];
+
+        let mut bytes = Vec::new();
+        let mut encoder = png::Encoder::new(&mut bytes, 2, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        if frames > 1 {
+            encoder.set_animated(frames, 0).unwrap();
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_chunk(png::chunk::eXIf, &exif).unwrap();
+        for _ in 0..frames {
+            writer
+                .write_image_data(&[255, 0, 0, 255, 0, 0, 255, 255])
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        bytes
+    }
+
+    #[test]
+    fn subject_scale_without_a_ring_lines_the_stencil_up_with_the_ring() {
+        let processor = ImageProcessor::new().unwrap();
+        let mut options = options(None);
+        assert_eq!(processor.subject_scale(&options).unwrap(), 1.0);
+
+        // oversized tokens are scaled up to fill the token ring
+        options.dimensions = ImageDimensions {
+            size: 16,
+            oversized: true,
+            stencil_radius: 6,
+        };
+        assert_eq!(processor.subject_scale(&options).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn exif_orientation_is_read_and_applied() {
+        let png = rotated_png(1);
+        let limits = ProcessingLimits::default();
+
+        let (_, orientation) = read_image(&png, &limits).unwrap();
+        assert_eq!(orientation, Orientation::Rotate90);
+        assert_eq!(read_dimensions(&png, &options(None)).unwrap(), (1, 2));
+
+        let image = decode_image(&png, &options(None), &limits).unwrap();
+        assert_eq!(image.oriented_dimensions(), (1, 2));
+    }
+
+    #[test]
+    fn orientation_override_replaces_the_exif_orientation() {
+        let png = rotated_png(1);
+        let limits = ProcessingLimits::default();
+
+        for (orientation, dimensions) in [
+            (ImageOrientation::NoTransforms, (2, 1)),
+            (ImageOrientation::Rotate180, (2, 1)),
+            (ImageOrientation::Rotate270, (1, 2)),
+        ] {
+            let options = options(Some(orientation));
+            assert_eq!(read_dimensions(&png, &options).unwrap(), dimensions);
+            let image = decode_image(&png, &options, &limits).unwrap();
+            assert_eq!(image.oriented_dimensions(), dimensions);
+        }
+    }
+
+    #[test]
+    fn animation_frames_keep_the_exif_orientation() {
+        let animation = decode_animation(&rotated_png(2), &ProcessingLimits::default())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(animation.frames.len(), 2);
+        assert_eq!(animation.orientation, Orientation::Rotate90);
+    }
+
+    #[cfg(feature = "tga")]
+    #[test]
+    fn only_plausible_tga_headers_are_tried_as_tga() {
+        // an uncompressed 2x1 true color image with 32 bits per pixel
+        let mut header = [0u8; 18];
+        header[2] = 2;
+        header[12] = 2;
+        header[14] = 1;
+        header[16] = 32;
+        assert!(looks_like_tga(&header));
+
+        let implausible = [(1, 2), (2, 4), (12, 0), (16, 12)];
+        for (index, value) in implausible {
+            let mut data = header;
+            data[index] = value;
+            assert!(!looks_like_tga(&data), "byte {index} set to {value}");
+        }
+        assert!(!looks_like_tga(&header[..17]));
+        assert!(!looks_like_tga(b"not an image, just some plain text"));
+    }
+}
@@ -1,3 +1,7 @@
+use serde::{Serialize, de::DeserializeOwned};
+use tsify::{Ts, Tsify};
+use wasm_bindgen::{JsError, JsValue};
+
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
     // `set_panic_hook` function at least once during initialization, and then
@@ -8,3 +12,18 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// Convert a value that was passed in from JavaScript, throwing an error if
+/// it does not have the expected shape.
+pub fn from_ts<T>(value: Ts<T>) -> Result<T, JsValue>
+where
+    T: Tsify + DeserializeOwned,
+    T::JsType: Clone,
+{
+    value.to_rust().map_err(|e| JsError::from(e).into())
+}
+
+/// Convert a value that is returned to JavaScript.
+pub fn into_ts<T: Tsify + Serialize>(value: &T) -> Result<Ts<T>, JsValue> {
+    value.into_ts().map_err(|e| JsError::from(e).into())
+}